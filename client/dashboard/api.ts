@@ -13,6 +13,7 @@ export interface ApiPage<T> {
     content: T[];
     remaining: number;
     limit: number;
+    next_cursor?: string;
 }
 
 export class Api {
@@ -49,6 +50,10 @@ export class Api {
         }
     }
 
+    events(path: string): EventSource {
+        return new EventSource(`${this.baseUrl}/${path}`);
+    }
+
     async get<T>(path: string): Promise<T> {
         const url = `${this.baseUrl}/${path}`;
         const response = await fetch(url);
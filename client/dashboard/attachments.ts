@@ -0,0 +1,219 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+import { getRelative } from "../util";
+import { Api, ApiPage } from "./api";
+import { Page, Router } from "./router";
+import { appendComponent } from "./util";
+
+const dateFormat = new Intl.DateTimeFormat([], {
+    year: 'numeric',
+    month: 'long',
+    day: 'numeric',
+    hour: 'numeric',
+    minute: 'numeric',
+});
+
+export interface Attachment {
+    id: number;
+    comment_id: number|null;
+    key: string;
+    thumbnail_key: string;
+    content_type: string;
+    size: number;
+    width: number;
+    height: number;
+    ip: string;
+    created: string;
+    created_timestamp: number;
+    url: string;
+    thumbnail_url: string;
+}
+
+export class Attachments implements Page {
+    offset = 0;
+    commentId?: number;
+    attachments?: ApiPage<Attachment>;
+
+    constructor(
+        private template: {
+            root: HTMLElement,
+            attachments: HTMLElement,
+            pageStart: HTMLElement,
+            pageEnd: HTMLElement,
+            total: HTMLElement,
+            filterAll: HTMLButtonElement,
+            refresh: HTMLButtonElement,
+            prev: HTMLButtonElement,
+            next: HTMLButtonElement,
+        },
+        private services: {
+            api: Api,
+            router: Router,
+        },
+    ) {
+        template.filterAll.onclick = () => this.applyFilter();
+        template.refresh.onclick = () => this.fetchAttachments();
+        template.next.onclick = () => this.next();
+        template.prev.onclick = () => this.prev();
+    }
+
+    enter(args: {offset: string, commentId: string}): void {
+        this.template.root.style.display = '';
+        if ('offset' in args) {
+            this.offset = parseInt(args.offset, 10);
+        }
+        this.commentId = args.commentId ? parseInt(args.commentId, 10) : undefined;
+        this.updateButtons();
+        this.fetchAttachments();
+    }
+
+    get args() {
+        return {
+            offset: '' + this.offset,
+            commentId: this.commentId ? '' + this.commentId : '',
+        };
+    }
+
+    pushState() {
+        this.services.router.pushState(['attachments'], this.args);
+    }
+
+    leave(): void {
+        this.template.root.style.display = 'none';
+    }
+
+    async applyFilter(commentId?: number) {
+        this.offset = 0;
+        this.commentId = commentId;
+        this.pushState();
+        await this.fetchAttachments();
+        this.updateButtons();
+    }
+
+    updateButtons(): void {
+        this.template.filterAll.setAttribute('aria-pressed', `${!this.commentId}`);
+    }
+
+    async fetchAttachments() {
+        this.template.attachments.classList.add('loading');
+        this.template.refresh.disabled = true;
+        this.template.prev.disabled = true;
+        this.template.next.disabled = true;
+        try {
+            let url = `admin/attachments?offset=${this.offset}`;
+            if (this.commentId) {
+                url += `&comment_id=${this.commentId}`;
+            }
+            this.attachments = await this.services.api.get<ApiPage<Attachment>>(url);
+            this.template.pageStart.textContent = `${this.offset + 1}`;
+            this.template.pageEnd.textContent = `${this.offset + this.attachments.content.length}`;
+            this.template.total.textContent = `${this.offset + this.attachments.content.length + this.attachments.remaining}`;
+            this.template.attachments.innerHTML = '';
+            this.attachments.content.forEach(attachment => {
+                appendComponent(this.template.attachments, AttachmentRow, attachmentTemplate,
+                    {attachment, api: this.services.api, router: this.services.router})
+            });
+            if (this.offset > 0) {
+                this.template.prev.disabled = false;
+            }
+            if (this.attachments.remaining > 0) {
+                this.template.next.disabled = false;
+            }
+        } catch (error) {
+            alert('Server error');
+        } finally {
+            this.template.attachments.classList.remove('loading');
+            this.template.refresh.disabled = false;
+        }
+    }
+
+    async next() {
+        if (!this.attachments || !this.attachments.remaining) {
+            return;
+        }
+        this.offset += this.attachments.limit;
+        this.pushState();
+        await this.fetchAttachments();
+        const clientRect = this.template.root.getBoundingClientRect();
+        if (clientRect.top < 0) {
+            window.scrollBy(0, clientRect.top);
+        }
+    }
+
+    async prev() {
+        if (!this.attachments || !this.offset) {
+            return;
+        }
+        this.offset = Math.max(0, this.offset - this.attachments.limit);
+        this.pushState();
+        await this.fetchAttachments();
+    }
+}
+
+const attachmentTemplate = `<div class="box-row attachment-row">
+    <a data-bind="link" target="_blank"><img data-bind="thumbnail" alt=""/></a>
+    <div class="attachment-info">
+        <time data-bind="created"></time>
+        <div data-bind="dimensions"></div>
+        <div data-bind="ip"></div>
+        <a href="#" data-bind="comment"></a>
+    </div>
+    <div class="button-group" style="margin-left: auto;">
+        <button data-bind="delete">Delete</button>
+    </div>
+</div>`;
+
+class AttachmentRow {
+    constructor(
+        private template: {
+            root: HTMLElement,
+            link: HTMLLinkElement,
+            thumbnail: HTMLImageElement,
+            created: HTMLTimeElement,
+            dimensions: HTMLElement,
+            ip: HTMLElement,
+            comment: HTMLLinkElement,
+            delete: HTMLButtonElement,
+        },
+        private data: {
+            attachment: Attachment,
+            api: Api,
+            router: Router,
+        }
+    ) {
+        const attachment = data.attachment;
+        template.link.href = attachment.url;
+        template.thumbnail.src = attachment.thumbnail_url;
+        const created = new Date(attachment.created_timestamp * 1000);
+        template.created.textContent = getRelative(created);
+        template.created.title = dateFormat.format(created);
+        template.created.dateTime = created.toISOString();
+        template.dimensions.textContent = `${attachment.width}×${attachment.height}, ${Math.ceil(attachment.size / 1024)} KiB`;
+        template.ip.textContent = attachment.ip;
+        if (attachment.comment_id) {
+            template.comment.textContent = `Comment #${attachment.comment_id}`;
+            data.router.link(template.comment, ['comments'], {
+                'filterType': 'id',
+                'filterValue': '' + attachment.comment_id,
+            });
+        } else {
+            template.comment.textContent = 'Not attached';
+            template.comment.removeAttribute('href');
+        }
+        template.delete.onclick = () => this.delete();
+    }
+
+    async delete() {
+        if (confirm('Are you sure you want to delete this image? It will be removed from the comment.')) {
+            try {
+                await this.data.api.delete(`admin/attachments/${this.data.attachment.id}`);
+                this.template.root.parentNode?.removeChild(this.template.root);
+            } catch (error) {
+                alert('Server error');
+            }
+        }
+    }
+}
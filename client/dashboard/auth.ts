@@ -5,6 +5,7 @@
 
 import { Api } from "./api";
 import { Emitter } from "./emitter";
+import { Role } from "./users";
 
 export interface Credentials {
     username: string;
@@ -19,7 +20,8 @@ export interface User {
     email: string,
     website: string,
     trusted: boolean,
-    admin: boolean,
+    role: Role,
+    scopes: string[],
 }
 
 export class Auth {
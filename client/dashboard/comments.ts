@@ -6,7 +6,7 @@
 import { getRelative } from "../util";
 import { Api, ApiPage } from "./api";
 import { Page, Router } from "./router";
-import { appendComponent } from "./util";
+import { appendComponent, prependComponent } from "./util";
 
 export type CommentStatus = 'Pending' | 'Approved' | 'Rejected';
 
@@ -25,6 +25,9 @@ export interface Comment {
     created: string;
     created_timestamp: number;
     replies: number;
+    deleted_at?: string;
+    references: number;
+    referenced_by: number;
 }
 
 type Filter = {
@@ -39,6 +42,12 @@ type Filter = {
 } | {
     type: 'id',
     value: number,
+} | {
+    type: 'references',
+    value: number,
+} | {
+    type: 'referenced_by',
+    value: number,
 };
 
 const dateFormat = new Intl.DateTimeFormat([], {
@@ -54,6 +63,8 @@ export class Comments implements Page {
     filter: Filter = {type: 'status', value: 'Pending'};
     asc: boolean = false;
     comments?: ApiPage<Comment>;
+    rows: Record<number, CommentRow> = {};
+    events?: EventSource;
 
     constructor(
         private template: {
@@ -117,12 +128,17 @@ export class Comments implements Page {
                 case 'parent_id':
                 case 'thread_id':
                 case 'id':
+                case 'references':
+                case 'referenced_by':
                     this.filter = {type: args.filterType, value: parseInt(args.filterValue, 10)};
                     break;
             }
         }
         this.updateButtons();
         this.fetchComments();
+        this.events = this.services.api.events('admin/events');
+        this.events.addEventListener('comment', e => this.onComment(JSON.parse((e as MessageEvent).data)));
+        this.events.addEventListener('delete', e => this.onDelete(JSON.parse((e as MessageEvent).data).id));
     }
 
     get args() {
@@ -140,6 +156,48 @@ export class Comments implements Page {
 
     leave(): void {
         this.template.root.style.display = 'none';
+        this.events?.close();
+        this.events = undefined;
+    }
+
+    matchesFilter(comment: Comment): boolean {
+        if (comment.deleted_at) {
+            return false;
+        }
+        switch (this.filter.type) {
+            case 'status':
+                return comment.status === this.filter.value;
+            case 'parent_id':
+                return comment.parent_id === this.filter.value;
+            case 'thread_id':
+                return comment.thread_id === this.filter.value;
+            case 'id':
+                return comment.id === this.filter.value;
+            case 'references':
+            case 'referenced_by':
+                return false;
+        }
+    }
+
+    onComment(comment: Comment) {
+        const row = this.rows[comment.id];
+        if (row) {
+            row.refresh(comment);
+        } else if (!this.offset && !this.asc && this.comments && this.matchesFilter(comment)) {
+            // New comments are shown at the top of the first page
+            this.rows[comment.id] = prependComponent(this.template.comments, CommentRow, commentTemplate,
+                {comment, api: this.services.api, router: this.services.router, comments: this});
+            this.template.pageEnd.textContent = `${parseInt(this.template.pageEnd.textContent || '0', 10) + 1}`;
+            this.template.total.textContent = `${parseInt(this.template.total.textContent || '0', 10) + 1}`;
+        }
+    }
+
+    onDelete(id: number) {
+        const row = this.rows[id];
+        if (row) {
+            row.remove();
+            delete this.rows[id];
+        }
     }
 
     updateButtons(): void {
@@ -170,8 +228,9 @@ export class Comments implements Page {
             this.template.pageEnd.textContent = `${this.offset + this.comments.content.length}`;
             this.template.total.textContent = `${this.offset + this.comments.content.length + this.comments.remaining}`;
             this.template.comments.innerHTML = '';
+            this.rows = {};
             this.comments.content.forEach(comment => {
-                appendComponent(this.template.comments, CommentRow, commentTemplate,
+                this.rows[comment.id] = appendComponent(this.template.comments, CommentRow, commentTemplate,
                     {comment, api: this.services.api, router: this.services.router, comments: this})
             });
             if (this.offset > 0) {
@@ -238,6 +297,8 @@ const commentTemplate = `<div class="box-row flex-column stretch">
             <div class="comment-actions">
                 <a href="" data-bind="replies"></a>
                 <a href="" data-bind="parent">Parent</a>
+                <a href="" data-bind="references"></a>
+                <a href="" data-bind="referencedBy"></a>
             </div>
         </div>
         <div data-bind="actions" class="button-group" style="margin-left: auto;">
@@ -266,6 +327,8 @@ class CommentRow {
             more: HTMLLinkElement,
             replies: HTMLLinkElement,
             parent: HTMLLinkElement,
+            references: HTMLLinkElement,
+            referencedBy: HTMLLinkElement,
             actions: HTMLElement,
             edit: HTMLButtonElement,
             approve: HTMLButtonElement,
@@ -302,6 +365,14 @@ class CommentRow {
             'filterType': 'id',
             'filterValue': '' + data.comment.parent_id,
         });
+        data.router.link(template.references, ['comments'], {
+            'filterType': 'referenced_by',
+            'filterValue': '' + data.comment.id,
+        });
+        data.router.link(template.referencedBy, ['comments'], {
+            'filterType': 'references',
+            'filterValue': '' + data.comment.id,
+        });
         template.approve.disabled = comment.status === 'Approved';
         template.reject.disabled = comment.status === 'Rejected';
         template.edit.onclick = () => this.edit();
@@ -343,7 +414,7 @@ class CommentRow {
         this.template.more.textContent = '[more]';
         this.expanded = false;
         this.data.comment = comment;
-        if (comment.website) {
+        if (/^https?:\/\//i.test(comment.website)) {
             const link = document.createElement('a');
             link.textContent = comment.name || 'Anonymous';
             link.href = comment.website;
@@ -371,6 +442,10 @@ class CommentRow {
         }
         this.template.content.textContent = comment.markdown;
         this.template.content.innerHTML = comment.html;
+        this.template.references.style.display = comment.references ? '' : 'none';
+        this.template.references.textContent = `Mentions ${comment.references}`;
+        this.template.referencedBy.style.display = comment.referenced_by ? '' : 'none';
+        this.template.referencedBy.textContent = `Mentioned by ${comment.referenced_by}`;
         if (this.template.content.getBoundingClientRect().height < this.template.content.scrollHeight) {
             this.template.more.style.display = '';
         } else {
@@ -389,6 +464,21 @@ class CommentRow {
         }
     }
 
+    refresh(comment: Comment) {
+        if (this.template.root.classList.contains('editting')) {
+            return;
+        }
+        this.template.comment.classList.remove(this.data.comment.status.toLowerCase());
+        this.update(comment);
+        this.template.comment.classList.add(comment.status.toLowerCase());
+        this.template.approve.disabled = comment.status === 'Approved';
+        this.template.reject.disabled = comment.status === 'Rejected';
+    }
+
+    remove() {
+        this.template.root.parentNode?.removeChild(this.template.root);
+    }
+
     closeEdit() {
         this.template.root.classList.remove('editting');
         this.template.editForm.innerHTML = '';
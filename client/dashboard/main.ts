@@ -4,6 +4,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 import { Api } from "./api";
+import { Attachments } from "./attachments";
 import { Auth } from "./auth";
 import { ChangePassword } from "./change-password";
 import { Comments } from "./comments";
@@ -26,6 +27,7 @@ const router = new Router({
     'comments': router => createComponent(Comments, document.getElementById('comments')!, {api, router}),
     'threads': router => createComponent(Threads, document.getElementById('threads')!, {api, router}),
     'users': router => createComponent(Users, document.getElementById('users')!, {api, router}),
+    'attachments': router => createComponent(Attachments, document.getElementById('attachments')!, {api, router}),
     'import': router => createComponent(Import, document.getElementById('import')!, {api, router}),
     'change-password': router => createComponent(ChangePassword, document.getElementById('change-password')!,
         {api, router}),
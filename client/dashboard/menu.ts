@@ -13,6 +13,7 @@ export class Menu {
             comments: HTMLLinkElement,
             threads: HTMLLinkElement,
             users: HTMLLinkElement,
+            attachments: HTMLLinkElement,
             import: HTMLLinkElement,
             changePassword: HTMLLinkElement,
             logOut: HTMLLinkElement,
@@ -26,6 +27,7 @@ export class Menu {
         services.router.link(template.comments, ['comments']);
         services.router.link(template.threads, ['threads']);
         services.router.link(template.users, ['users']);
+        services.router.link(template.attachments, ['attachments']);
         services.router.link(template.import, ['import']);
         services.router.link(template.changePassword, ['change-password']);
         template.logOut.onclick = e => this.logOut(e);
@@ -45,17 +47,12 @@ export class Menu {
     }
 
     userChange(user: User|undefined) {
-        if (user && user.admin) {
-            this.template.comments.style.display = '';
-            this.template.threads.style.display = '';
-            this.template.users.style.display = '';
-            this.template.import.style.display = '';
-        } else {
-            this.template.comments.style.display = 'none';
-            this.template.threads.style.display = 'none';
-            this.template.users.style.display = 'none';
-            this.template.import.style.display = 'none';
-        }
+        const scopes = user ? user.scopes : [];
+        this.template.comments.style.display = scopes.indexOf('comments:read') >= 0 ? '' : 'none';
+        this.template.threads.style.display = scopes.indexOf('threads:read') >= 0 ? '' : 'none';
+        this.template.users.style.display = scopes.indexOf('users') >= 0 ? '' : 'none';
+        this.template.attachments.style.display = scopes.indexOf('comments:read') >= 0 ? '' : 'none';
+        this.template.import.style.display = scopes.indexOf('import') >= 0 ? '' : 'none';
         if (user) {
             this.template.changePassword.style.display = '';
             this.template.logOut.style.display = '';
@@ -3,6 +3,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+import { getRelative } from "../util";
 import { Api, ApiPage } from "./api";
 import { Page, Router } from "./router";
 import { appendComponent, prependComponent } from "./util";
@@ -11,9 +12,27 @@ export interface Thread {
     id: number;
     name: string;
     title: string;
+    url?: string;
     comments: number;
+    deleted_at?: string;
+    locked: boolean;
+    closes_at?: string;
+    moderate_all?: boolean;
+    max_depth?: number;
+    require_name?: boolean;
+    require_email?: boolean;
+    close_after?: number;
+    created: string;
 }
 
+const dateFormat = new Intl.DateTimeFormat([], {
+    year: 'numeric',
+    month: 'long',
+    day: 'numeric',
+    hour: 'numeric',
+    minute: 'numeric',
+});
+
 type Filter = {
     type: 'id',
     value: number,
@@ -96,6 +115,8 @@ export class Threads implements Page {
                 name: '',
                 title: '',
                 comments: 0,
+                locked: false,
+                created: '',
             },
             api: this.services.api,
             router: this.services.router,
@@ -173,11 +194,14 @@ export class Threads implements Page {
 const threadTemplate = `<div class="box-row flex-column stretch">
     <div class="thread-row" data-bind="thread">
         <div class="thread-info">
-            <div data-bind="name"></div>
             <div data-bind="title"></div>
+            <div data-bind="name"></div>
+            <a href="" target="_blank" rel="noopener noreferrer" data-bind="url"></a>
+            <time data-bind="created"></time>
             <a href="" data-bind="comments"></a>
         </div>
         <div data-bind="actions" class="button-group" style="margin-left: auto;">
+            <button data-bind="refresh" title="Refresh title and URL">Refresh</button>
             <button data-bind="edit">Edit</button>
         </div>
     </div>
@@ -194,8 +218,11 @@ class ThreadRow {
             thread: HTMLElement,
             name: HTMLElement,
             title: HTMLElement,
+            url: HTMLLinkElement,
+            created: HTMLTimeElement,
             comments: HTMLLinkElement,
             actions: HTMLElement,
+            refresh: HTMLButtonElement,
             edit: HTMLButtonElement,
             editForm: HTMLElement,
         },
@@ -211,6 +238,7 @@ class ThreadRow {
         this.update(thread);
         template.comments.textContent = (n => n === 1 ? `${n} comments` : `${n} comments`)(thread.comments);
         template.comments.onclick = e => this.comments(e);
+        template.refresh.onclick = () => this.refresh();
         template.edit.onclick = () => this.edit();
         if (data.isNew) {
             this.edit();
@@ -248,6 +276,26 @@ class ThreadRow {
         this.template.name.textContent = thread.name;
         this.template.title.textContent = thread.title;
         this.template.title.style.display = thread.title ? '' : 'none';
+        this.template.url.textContent = thread.url || '';
+        this.template.url.href = thread.url || '';
+        this.template.url.style.display = thread.url ? '' : 'none';
+        if (thread.created) {
+            const created = new Date(thread.created);
+            this.template.created.textContent = getRelative(created);
+            this.template.created.title = dateFormat.format(created);
+            this.template.created.dateTime = created.toISOString();
+        }
+    }
+
+    async refresh() {
+        this.template.refresh.disabled = true;
+        try {
+            this.update(await this.data.api.post<Thread>(`admin/threads/${this.data.thread.id}/refresh`, {}));
+        } catch (error) {
+            alert('Unable to refresh thread');
+        } finally {
+            this.template.refresh.disabled = false;
+        }
     }
 
     async delete() {
@@ -285,6 +333,12 @@ const threadFormTemplate = `<form class="margin-top">
             <input type="text" data-bind="title"/>
         </label>
     </div>
+    <div class="field">
+        <label>
+            <input type="checkbox" data-bind="locked"/>
+            Locked
+        </label>
+    </div>
     <div class="flex-row space-between">
         <button data-bind="cancel" type="button">Cancel</button>
         <div>
@@ -300,6 +354,7 @@ class ThreadForm {
             root: HTMLFormElement,
             name: HTMLInputElement,
             title: HTMLInputElement,
+            locked: HTMLInputElement,
             cancel: HTMLButtonElement,
             delete: HTMLButtonElement,
             submit: HTMLButtonElement,
@@ -315,6 +370,7 @@ class ThreadForm {
     ) {
         template.name.value = data.thread.name;
         template.title.value = data.thread.title;
+        template.locked.checked = data.thread.locked;
         if (this.data.isNew) {
             template.delete.style.display = 'none';
         } else {
@@ -335,8 +391,16 @@ class ThreadForm {
                     title: this.template.title.value,
                 }));
             } else {
-                this.data.onSave(await this.data.api.put<Thread>(`admin/threads/${this.data.thread.id}`, {
+                const thread = this.data.thread;
+                this.data.onSave(await this.data.api.put<Thread>(`admin/threads/${thread.id}`, {
                     title: this.template.title.value,
+                    locked: this.template.locked.checked,
+                    closes_at: thread.closes_at,
+                    moderate_all: thread.moderate_all,
+                    max_depth: thread.max_depth,
+                    require_name: thread.require_name,
+                    require_email: thread.require_email,
+                    close_after: thread.close_after,
                 }));
             }
         } catch (error) {
@@ -14,9 +14,11 @@ export interface User {
     email: string;
     website: string;
     trusted: boolean;
-    admin: boolean;
+    role: Role;
 }
 
+export type Role = 'User' | 'Moderator' | 'ThreadManager' | 'Admin';
+
 type Filter = {
     type: 'id',
     value: number,
@@ -101,7 +103,7 @@ export class Users implements Page {
                 email: '',
                 website: '',
                 trusted: false,
-                admin: false,
+                role: 'User',
             },
             api: this.services.api,
             router: this.services.router,
@@ -322,8 +324,13 @@ const userFormTemplate = `<form class="margin-top">
     </div>
     <div class="field">
         <label>
-            <input type="checkbox" data-bind="admin"/>
-            Admin
+            Role
+            <select data-bind="role">
+                <option value="User">User</option>
+                <option value="Moderator">Moderator</option>
+                <option value="ThreadManager">Thread manager</option>
+                <option value="Admin">Admin</option>
+            </select>
         </label>
     </div>
     <div class="flex-row space-between">
@@ -348,7 +355,7 @@ class UserForm {
             email: HTMLInputElement,
             website: HTMLInputElement,
             trusted: HTMLInputElement,
-            admin: HTMLInputElement,
+            role: HTMLSelectElement,
             cancel: HTMLButtonElement,
             delete: HTMLButtonElement,
             submit: HTMLButtonElement,
@@ -375,7 +382,7 @@ class UserForm {
         template.email.value = data.user.email;
         template.website.value = data.user.website;
         template.trusted.checked = data.user.trusted;
-        template.admin.checked = data.user.admin;
+        template.role.value = data.user.role;
         template.cancel.onclick = () => data.onCancel();
         template.delete.onclick = () => data.onDelete();
         template.root.onsubmit = e => this.submit(e);
@@ -400,7 +407,7 @@ class UserForm {
                     email: this.template.email.value,
                     website: this.template.website.value,
                     trusted: this.template.trusted.checked,
-                    admin: this.template.admin.checked,
+                    role: this.template.role.value,
                 }));
             } else {
                 this.data.onSave(await this.data.api.put<User>(`admin/users/${this.data.user.id}`, {
@@ -409,7 +416,7 @@ class UserForm {
                     email: this.template.email.value,
                     website: this.template.website.value,
                     trusted: this.template.trusted.checked,
-                    admin: this.template.admin.checked,
+                    role: this.template.role.value,
                 }));
             }
         } catch (error) {
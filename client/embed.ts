@@ -10,7 +10,7 @@ import { getRelative } from './util';
 require('./slim.scss');
 
 const mainTemplate = '<div data-bind="commentCount" class="comment-count"></div><form data-bind="newCommentForm"></form><div class="comments" data-bind="comments"></div>';
-const formTemplate = `<div class="commenter-info"><input type="text" name="name" data-bind="name" placeholder="${language.name}"/><input type="email" name="email" data-bind="email" placeholder="${language.email}"/><input type="url" name="website" data-bind="website" placeholder="${language.website}"/></div><textarea name="content" data-bind="content" placeholder="${language.comment}" required></textarea><div class="buttons"><button type="submit">${language.submit}</button></div>`;
+const formTemplate = `<div class="commenter-info"><input type="text" name="name" data-bind="name" placeholder="${language.name}"/><input type="email" name="email" data-bind="email" placeholder="${language.email}"/><input type="url" name="website" data-bind="website" placeholder="${language.website}"/></div><textarea name="content" data-bind="content" placeholder="${language.comment}" required></textarea><div class="comment-preview" data-bind="preview" style="display: none;"></div><div class="buttons"><input type="file" accept="image/png,image/jpeg,image/gif,image/webp" data-bind="file" style="display: none;"/><button type="button" data-bind="attachButton" style="display: none;">${language.attachImage}</button> <button type="button" data-bind="previewButton">${language.preview}</button> <button type="submit">${language.submit}</button></div>`;
 const commentTemplate = `<div class="comment" data-bind="comment"><div class="comment-header"><span class="author" data-bind="author"></span><time data-bind="created"></time></div><div class="comment-body" data-bind="content"></div><div class="comment-actions"><a href="#" data-bind="replyLink">${language.reply}</a></div><form data-bind="replyForm"></form><div class="replies" data-bind="replies"></div></div>`;
 
 declare const LANGUAGE: string;
@@ -44,6 +44,10 @@ interface FormTemplate {
     email: HTMLInputElement;
     website: HTMLInputElement;
     content: HTMLTextAreaElement;
+    preview: HTMLElement;
+    previewButton: HTMLButtonElement;
+    file: HTMLInputElement;
+    attachButton: HTMLButtonElement;
 }
 
 interface CommentTemplate {
@@ -65,6 +69,7 @@ interface Config {
     requireName: boolean;
     requireEmail: boolean;
     clickToLoad: boolean;
+    attachments: boolean;
 }
 
 interface Comment {
@@ -76,7 +81,17 @@ interface Comment {
     created: string;
     created_timestamp: number;
     approved: boolean;
+    deleted: boolean;
+    hidden: boolean;
     replies: Comment[];
+    author_token?: string;
+}
+
+interface CommentThread {
+    open: boolean;
+    require_name: boolean;
+    require_email: boolean;
+    comments: Comment[];
 }
 
 interface NewComment {
@@ -86,6 +101,62 @@ interface NewComment {
     content: string;
 }
 
+function getAuthorTokens(config: Config): string[] {
+    try {
+        return JSON.parse(localStorage.getItem(`uncomment_author_${config.id}`) || '[]');
+    } catch (error) {
+        return [];
+    }
+}
+
+function addAuthorToken(config: Config, token: string) {
+    const tokens = getAuthorTokens(config);
+    tokens.push(token);
+    localStorage.setItem(`uncomment_author_${config.id}`, JSON.stringify(tokens.slice(-20)));
+}
+
+async function previewComment(config: Config, content: string): Promise<string> {
+    const response = await fetch(`${config.api}/preview`, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({t: config.id, content}),
+    });
+    if (!response.ok) {
+        throw new Error(await response.text());
+    }
+    return (await response.json()).html;
+}
+
+async function uploadAttachment(config: Config, file: File): Promise<string> {
+    const data = new FormData();
+    data.append('file', file);
+    const response = await fetch(`${config.api}/attachments`, {
+        method: 'POST',
+        body: data,
+    });
+    if (!response.ok) {
+        switch (await response.text()) {
+            case 'ATTACHMENT_TOO_LARGE':
+                alert(language.attachmentTooLargeError);
+                break;
+            case 'UNSUPPORTED_TYPE':
+            case 'INVALID_IMAGE':
+                alert(language.invalidImageError);
+                break;
+            case 'TOO_MANY_ATTACHMENTS':
+                alert(language.tooManyAttachmentsError);
+                break;
+            default:
+                alert(language.unknownError);
+                break;
+        }
+        throw new Error();
+    }
+    return (await response.json()).markdown;
+}
+
 async function postComment(config: Config, data: NewComment, parentId?: number): Promise<Comment> {
     let url = `${config.api}/comments?t=${config.id}`;
     if (parentId != undefined) {
@@ -109,16 +180,30 @@ async function postComment(config: Config, data: NewComment, parentId?: number):
             case 'MISSING_EMAIL':
                 alert(language.missingEmailError);
                 break;
+            case 'INVALID_WEBSITE':
+                alert(language.invalidWebsiteError);
+                break;
             case 'TOO_MANY_COMMENTS':
                 alert(language.tooManyCommentsError);
                 break;
+            case 'TOO_MANY_LINKS':
+                alert(language.tooManyLinksError);
+                break;
+            case 'THREAD_LOCKED':
+            case 'THREAD_CLOSED':
+                alert(language.threadClosedError);
+                break;
             default:
                 alert(language.unknownError);
                 break;
         }
         throw new Error();
     }
-    return response.json();
+    const comment: Comment = await response.json();
+    if (comment.author_token && !comment.approved) {
+        addAuthorToken(config, comment.author_token);
+    }
+    return comment;
 }
 
 function createCommentForm(
@@ -126,13 +211,55 @@ function createCommentForm(
     form: HTMLFormElement,
     parentId: number|undefined,
     onSuccess: (comment: Comment, template: FormTemplate) => void,
-) {
+): FormTemplate {
     const template: FormTemplate = applyTemplate(form, formTemplate);
     template.name.value = localStorage.getItem('uncomment_name') || '';
     template.name.required = config.requireName;
     template.email.value = localStorage.getItem('uncomment_email') || '';
     template.email.required = config.requireEmail;
     template.website.value = localStorage.getItem('uncomment_website') || '';
+    if (config.attachments) {
+        template.attachButton.style.display = '';
+        template.attachButton.onclick = () => template.file.click();
+        template.file.onchange = async () => {
+            const file = template.file.files?.[0];
+            template.file.value = '';
+            if (!file) {
+                return;
+            }
+            template.attachButton.disabled = true;
+            try {
+                const markdown = await uploadAttachment(config, file);
+                const content = template.content;
+                const before = content.value.slice(0, content.selectionStart);
+                const after = content.value.slice(content.selectionEnd);
+                const separator = before && !before.endsWith('\n') ? '\n' : '';
+                content.value = `${before}${separator}${markdown}\n${after}`;
+                content.focus();
+            } catch (error) {
+                return;
+            } finally {
+                template.attachButton.disabled = false;
+            }
+        };
+    }
+    template.previewButton.onclick = async () => {
+        if (template.preview.style.display !== 'none') {
+            template.preview.style.display = 'none';
+            template.content.style.display = '';
+            template.previewButton.textContent = language.preview;
+            return;
+        }
+        try {
+            template.preview.innerHTML = await previewComment(config, template.content.value);
+        } catch (error) {
+            alert(language.unknownError);
+            return;
+        }
+        template.preview.style.display = '';
+        template.content.style.display = 'none';
+        template.previewButton.textContent = language.edit;
+    };
     form.onsubmit = async e => {
         e.preventDefault();
         localStorage.setItem('uncomment_name', template.name.value);
@@ -144,8 +271,12 @@ function createCommentForm(
             website: template.website.value,
             content: template.content.value,
         }, parentId);
+        template.preview.style.display = 'none';
+        template.content.style.display = '';
+        template.previewButton.textContent = language.preview;
         onSuccess(comment, template);
     };
+    return template;
 }
 
 function addCommentToContainer(
@@ -159,33 +290,39 @@ function addCommentToContainer(
     const template = applyTemplate<CommentTemplate>(temp, commentTemplate);
     allComments[comment.id] = template;
     template.comment.id = `comment-${comment.id}`;
-    if (!comment.name) {
+    if (comment.deleted) {
+        template.comment.classList.add('uncomment-deleted');
+        comment.name = language.deleted;
+    } else if (comment.hidden) {
+        template.comment.classList.add('uncomment-hidden');
+        comment.name = language.hidden;
+    } else if (!comment.name) {
         comment.name = language.anonymous;
     }
-    if (comment.website) {
+    if (/^https?:\/\//i.test(comment.website)) {
         const link = document.createElement('a');
         link.textContent = comment.name;
         link.href = comment.website;
-        link.rel = 'noopener noreferrer'; 
+        link.rel = 'nofollow ugc noopener noreferrer'; 
         template.author.appendChild(link);
     } else {
         template.author.textContent = comment.name;
     }
     const permalink = document.createElement('a');
     const created = new Date(comment.created_timestamp * 1000);
-    if (comment.approved) {
+    if (comment.approved || comment.hidden) {
     permalink.textContent = config.relativeDates ? getRelative(created) : dateFormat.format(created);
     } else {
         permalink.textContent = language.pendingReview;
     }
     permalink.href = `#${template.comment.id}`;
     template.created.appendChild(permalink);
-    if (config.relativeDates || !comment.approved) {
+    if (config.relativeDates || (!comment.approved && !comment.hidden)) {
         template.created.title = dateFormat.format(created);
     }
     template.created.dateTime = created.toISOString();
     template.content.innerHTML = comment.html;
-    if (comment.approved) {
+    if (comment.approved && !comment.hidden) {
     let replyFormOpen = false;
         template.replyLink.onclick = e => {
             e.preventDefault();
@@ -198,7 +335,9 @@ function addCommentToContainer(
                     template.replyForm.innerHTML = '';
                     replyFormOpen = false;
                     template.replyLink.textContent = language.reply;
-                    if (allComments[reply.parent_id]) {
+                    if (allComments[reply.id]) {
+                        allComments[reply.id].comment.scrollIntoView();
+                    } else if (allComments[reply.parent_id]) {
                         const elem = addCommentToContainer(config, allComments[reply.parent_id].replies, reply, allComments, config.newestFirst);
                         elem.scrollIntoView();
                     }
@@ -218,16 +357,54 @@ function addCommentToContainer(
     }
 }
 
-async function loadComments(config: Config, container: Element, allComments: Record<number, CommentTemplate>) {
+function listenForChanges(config: Config, container: Element, allComments: Record<number, CommentTemplate>) {
+    const events = new EventSource(`${config.api}/threads/${encodeURI(config.id)}/events`);
+    events.addEventListener('comment', e => {
+        const comment: Comment = JSON.parse((e as MessageEvent).data);
+        if (allComments[comment.id]) {
+            allComments[comment.id].content.innerHTML = comment.html;
+        } else if (!comment.parent_id) {
+            addCommentToContainer(config, container, comment, allComments, config.newestFirst);
+        } else if (allComments[comment.parent_id]) {
+            addCommentToContainer(config, allComments[comment.parent_id].replies, comment, allComments,
+                config.newestFirst);
+        }
+    });
+    events.addEventListener('delete', e => {
+        const template = allComments[JSON.parse((e as MessageEvent).data).id];
+        if (template) {
+            template.comment.classList.add('uncomment-deleted');
+            template.author.textContent = language.deleted;
+            template.content.innerHTML = '';
+            template.replyLink.style.display = 'none';
+        }
+    });
+}
+
+async function loadComments(
+    config: Config,
+    container: Element,
+    allComments: Record<number, CommentTemplate>,
+    onLoad: (thread: CommentThread) => void,
+) {
     try {
-        const response = await fetch(`${config.api}/comments?t=${config.id}&newest_first=${config.newestFirst}`);
+        let url = `${config.api}/comments?t=${config.id}&newest_first=${config.newestFirst}`;
+        const authorTokens = getAuthorTokens(config);
+        if (authorTokens.length) {
+            url += `&author=${encodeURIComponent(authorTokens.join(','))}`;
+        }
+        const response = await fetch(url);
         if (!response.ok) {
             throw new Error(await response.text());
         }
-        const comments: Comment[] = await response.json();
-        for (let comment of comments) {
+        const thread: CommentThread = await response.json();
+        onLoad(thread);
+        for (let comment of thread.comments) {
             addCommentToContainer(config, container, comment, allComments);
         }
+        if (typeof EventSource !== 'undefined') {
+            listenForChanges(config, container, allComments);
+        }
     } catch (error) {
         console.error('Unable to fetch comments', error);
         const description = document.createElement('div');
@@ -239,7 +416,7 @@ async function loadComments(config: Config, container: Element, allComments: Rec
         container.appendChild(retry);
         retry.onclick = () => {
             container.innerHTML = '';
-            loadComments(config, container, allComments);
+            loadComments(config, container, allComments, onLoad);
         };
     }
 }
@@ -250,21 +427,38 @@ function load(config: Config) {
     const main = applyTemplate<MainTemplate>(config.target, mainTemplate);
     main.commentCount.setAttribute('data-uncomment-count', config.id);
     initCommentCounts(config.api);
-    createCommentForm(config, main.newCommentForm, undefined, (comment, template) => {
+    const form = createCommentForm(config, main.newCommentForm, undefined, (comment, template) => {
         template.content.value = '';
+        if (allComments[comment.id]) {
+            allComments[comment.id].comment.scrollIntoView();
+            return;
+        }
         const elem = addCommentToContainer(config, main.comments, comment, allComments, config.newestFirst);
         elem.scrollIntoView();
     });
+    const onLoad = (thread: CommentThread) => {
+        config.requireName = config.requireName || thread.require_name;
+        config.requireEmail = config.requireEmail || thread.require_email;
+        form.name.required = config.requireName;
+        form.email.required = config.requireEmail;
+        if (!thread.open) {
+            config.target.classList.add('uncomment-closed');
+            const closed = document.createElement('div');
+            closed.className = 'uncomment-closed-message';
+            closed.textContent = language.threadClosed;
+            main.newCommentForm.parentNode?.replaceChild(closed, main.newCommentForm);
+        }
+    };
     if (config.clickToLoad) {
         const button = document.createElement('button');
         button.textContent = language.loadComments;
         main.comments.appendChild(button);
         button.onclick = () => {
             main.comments.innerHTML = '';
-            loadComments(config, main.comments, allComments);
+            loadComments(config, main.comments, allComments, onLoad);
         };
     } else {
-        loadComments(config, main.comments, allComments);
+        loadComments(config, main.comments, allComments, onLoad);
     }
 }
 
@@ -298,6 +492,7 @@ function initFromScriptTag() {
         requireName: script.getAttribute('data-uncomment-require-name') === 'true',
         requireEmail: script.getAttribute('data-uncomment-require-email') === 'true',
         clickToLoad: script.getAttribute('data-uncomment-click-to-load') === 'true',
+        attachments: script.getAttribute('data-uncomment-attachments') === 'true',
     };
     load(config);
 }
@@ -7,14 +7,26 @@ export const language = {
     submit: 'Send',
     reply: 'Svar',
     cancel: 'Annullér',
+    preview: 'Forhåndsvis',
+    edit: 'Rediger',
+    attachImage: 'Vedhæft billede',
     anonymous: 'Anonym',
+    deleted: '[slettet]',
+    hidden: '[skjult]',
     pendingReview: 'Afventer godkendelse',
     loadComments: 'Hent kommentarer',
     commentLoadError: 'Kommentarerne kunne ikke indlæses',
+    threadClosed: 'Der er lukket for kommentarer',
     missingContentError: 'Kommentaren kan ikke være tom',
     missingNameError: 'Anonyme kommentarer er ikke tilladt',
     missingEmailError: 'En email er nødvendig',
+    invalidWebsiteError: 'Websitet skal starte med http:// eller https://',
     tooManyCommentsError: 'For mange kommentarer',
+    tooManyLinksError: 'Kommentaren indeholder for mange links',
+    threadClosedError: 'Tråden er lukket for nye kommentarer',
+    attachmentTooLargeError: 'Billedet er for stort',
+    invalidImageError: 'Kun PNG-, JPEG-, GIF- og WebP-billeder kan vedhæftes',
+    tooManyAttachmentsError: 'For mange billeder uploadet, prøv igen senere',
     unknownError: 'Der opstod en ukendt fejl',
     minutes: (n: number) => n === 1 ? `et minut siden` : `${n} minutter siden`,
     hours: (n: number) => n === 1 ? `en time siden` : `${n} timer siden`,
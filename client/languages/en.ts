@@ -7,14 +7,26 @@ export const language = {
     submit: 'Submit',
     reply: 'Reply',
     cancel: 'Cancel',
+    preview: 'Preview',
+    edit: 'Edit',
+    attachImage: 'Attach image',
     anonymous: 'Anonymous',
+    deleted: '[deleted]',
+    hidden: '[hidden]',
     pendingReview: 'Pending review',
     loadComments: 'Load comments',
     commentLoadError: 'Comments failed to load',
+    threadClosed: 'Comments are closed',
     missingContentError: 'Comment cannot be empty',
     missingNameError: 'Anonymous comments are not allowed',
     missingEmailError: 'An email is required',
+    invalidWebsiteError: 'The website must start with http:// or https://',
     tooManyCommentsError: 'Too many comments',
+    tooManyLinksError: 'Comment contains too many links',
+    threadClosedError: 'This thread is closed for new comments',
+    attachmentTooLargeError: 'The image is too large',
+    invalidImageError: 'Only PNG, JPEG, GIF and WebP images can be attached',
+    tooManyAttachmentsError: 'Too many images uploaded, try again later',
     unknownError: 'An unknown error occurred',
     minutes: (n: number) => n === 1 ? `a minute ago` : `${n} minutes ago`,
     hours: (n: number) => n === 1 ? `an hour ago` : `${n} hours ago`,
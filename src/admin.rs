@@ -8,24 +8,82 @@
 use actix_multipart::Multipart;
 use actix_web::{HttpResponse, delete, error, get, put, post, web};
 use log::info;
-use pulldown_cmark::Parser;
+use serde_json::json;
+use chrono::{DateTime, Utc};
 use futures::{TryStreamExt, StreamExt};
-use std::io::{Seek, SeekFrom, Write};
+use std::{cmp, io::{Seek, SeekFrom, Write}};
 
-use crate::{auth::{self, hash_password}, db::{Pool, comments::{self, CommentFilter, CommentStatus, UpdateComment}, threads::{self, NewThread, UpdateThread}, users::{self, NewUser, UpdateUser}}, import, settings::Settings};
+use crate::{attachments::{self, AttachmentInfo}, auth::{self, hash_password}, db::{self, DbError, Pool, audit::{self, AuditAction, AuditFilter, NewAuditEntry}, comments::{self, BulkAction, BulkFilter, CommentCursor, CommentFilter, CommentSort, CommentStatus, UpdateComment}, mentions, threads::{self, NewThread, UpdateThread}, tokens::{self, NewToken, Scope, Token}, users::{self, NewUser, UpdateUser, User}}, events::{self, Broadcaster}, html::{self, get_base_url}, import, metadata, normalize::normalize_thread_name, render, rerender, settings::Settings, storage::Storage};
+
+/// Parses an RFC 3339 timestamp in a query string. A `+` in the offset is
+/// decoded as a space unless it's percent-encoded, so spaces are accepted in
+/// its place.
+fn parse_query_time(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_rfc3339(&value.replace(' ', "+")))
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+fn deserialize_query_time<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    match value {
+        Some(value) => parse_query_time(&value).map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid timestamp: {}", value))),
+        None => Ok(None),
+    }
+}
 
 #[derive(serde::Deserialize)]
 struct CommentQuery {
     offset: Option<usize>,
+    limit: Option<usize>,
+    cursor: Option<String>,
     status: Option<CommentStatus>,
     parent_id: Option<i32>,
     thread_id: Option<i32>,
+    ip: Option<String>,
+    email: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_query_time")]
+    since: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "deserialize_query_time")]
+    until: Option<DateTime<Utc>>,
+    has_replies: Option<bool>,
+    references: Option<i32>,
+    referenced_by: Option<i32>,
+    q: Option<String>,
+    sort: Option<CommentSort>,
     asc: Option<bool>,
+    deleted: Option<bool>,
 }
 
 #[derive(serde::Deserialize)]
 struct ThreadQuery {
     offset: Option<usize>,
+    deleted: Option<bool>,
+}
+
+#[derive(serde::Deserialize)]
+struct DeleteQuery {
+    permanent: Option<bool>,
+}
+
+#[derive(serde::Deserialize)]
+struct MoveCommentData {
+    thread_id: i32,
+}
+
+#[derive(serde::Deserialize)]
+struct MergeThreadData {
+    target_thread_id: i32,
+}
+
+#[derive(serde::Deserialize)]
+struct NewThreadAliasData {
+    name: String,
 }
 
 #[derive(serde::Deserialize)]
@@ -33,6 +91,65 @@ struct UserQuery {
     offset: Option<usize>,
 }
 
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq)]
+enum BulkActionData {
+    Approve,
+    Reject,
+    Delete,
+    Move,
+}
+
+#[derive(serde::Deserialize)]
+struct BulkFilterData {
+    status: Option<CommentStatus>,
+    thread_id: Option<i32>,
+    ip: Option<String>,
+    email: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Deserialize)]
+struct BulkData {
+    ids: Option<Vec<i32>>,
+    filter: Option<BulkFilterData>,
+    action: BulkActionData,
+    target_thread_id: Option<i32>,
+}
+
+#[derive(serde::Deserialize)]
+struct RerenderData {
+    ids: Option<Vec<i32>>,
+    filter: Option<BulkFilterData>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct AttachmentQuery {
+    offset: Option<usize>,
+    comment_id: Option<i32>,
+}
+
+#[derive(serde::Deserialize)]
+struct AuditQuery {
+    offset: Option<usize>,
+    user_id: Option<i32>,
+    action: Option<AuditAction>,
+    ip: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_query_time")]
+    since: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "deserialize_query_time")]
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize)]
+struct CreatedToken {
+    #[serde(flatten)]
+    token: Token,
+    secret: String,
+}
+
 #[derive(serde::Deserialize)]
 struct UpdateCommentData {
     name: String,
@@ -42,18 +159,89 @@ struct UpdateCommentData {
     status: CommentStatus,
 }
 
+async fn log_action(
+    pool: &Pool,
+    user: &User,
+    ip: String,
+    action: AuditAction,
+    details: serde_json::Value,
+) -> Result<(), DbError> {
+    audit::create_audit_entry(pool, NewAuditEntry {
+        user_id: Some(user.id),
+        username: user.username.clone(),
+        action,
+        ip,
+        details,
+    }).await
+}
+
+/// Records an action in the audit log as part of the transaction that
+/// performs it.
+async fn log_action_tx(
+    tx: &mut db::Transaction,
+    user: &User,
+    ip: String,
+    action: AuditAction,
+    details: serde_json::Value,
+) -> Result<(), DbError> {
+    audit::create_audit_entry_tx(tx, NewAuditEntry {
+        user_id: Some(user.id),
+        username: user.username.clone(),
+        action,
+        ip,
+        details,
+    }).await
+}
+
+async fn find_comments(pool: &Pool, query: CommentQuery) -> actix_web::Result<HttpResponse> {
+    let cursor = match &query.cursor {
+        Some(cursor) => Some(CommentCursor::parse(cursor).ok_or_else(|| error::ErrorBadRequest("INVALID_CURSOR"))?),
+        None => None,
+    };
+    let mut filter = CommentFilter {
+        status: query.status,
+        parent_id: query.parent_id,
+        thread_id: query.thread_id,
+        ip: query.ip,
+        email: query.email,
+        since: query.since,
+        until: query.until,
+        has_replies: query.has_replies,
+        references: query.references,
+        referenced_by: query.referenced_by,
+        search: query.q.filter(|q| !q.trim().is_empty()),
+        deleted: query.deleted.unwrap_or(false),
+    };
+    if filter.parent_id.is_none() && filter.thread_id.is_none() && filter.references.is_none()
+        && filter.referenced_by.is_none() && filter.search.is_none() && !filter.deleted {
+        filter.status = filter.status.or(Some(CommentStatus::Pending));
+    }
+    let limit = cmp::max(1, cmp::min(100, query.limit.unwrap_or(10)));
+    Ok(HttpResponse::Ok().json(comments::get_comments(&pool, &filter, query.sort.unwrap_or(CommentSort::Created),
+        query.asc.unwrap_or(false), limit, query.offset.unwrap_or(0), cursor.as_ref()).await?))
+}
+
 #[get("/admin/comments")]
 async fn get_comments(
     request: web::HttpRequest,
     pool: web::Data<Pool>,
     query: web::Query<CommentQuery>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
-    let filter = query.parent_id.map(|id| CommentFilter::Parent(id))
-        .unwrap_or_else(|| query.thread_id.map(|id| CommentFilter::Thread(id))
-            .unwrap_or_else(|| CommentFilter::Status(query.status.unwrap_or(CommentStatus::Pending))));
-    Ok(HttpResponse::Ok().json(comments::get_comments(&pool, filter,
-                query.asc.unwrap_or(false), 10, query.offset.unwrap_or(0)).await?))
+    auth::validate_access(request, &pool, Scope::CommentsRead).await?;
+    find_comments(&pool, query.into_inner()).await
+}
+
+#[get("/admin/comments/search")]
+async fn search_comments(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    query: web::Query<CommentQuery>,
+) -> actix_web::Result<HttpResponse> {
+    auth::validate_access(request, &pool, Scope::CommentsRead).await?;
+    if query.q.as_ref().map(|q| q.trim().is_empty()).unwrap_or(true) {
+        Err(error::ErrorBadRequest("MISSING_QUERY"))?;
+    }
+    find_comments(&pool, query.into_inner()).await
 }
 
 #[get("/admin/comments/{id:\\d+}")]
@@ -62,7 +250,7 @@ async fn get_comment(
     pool: web::Data<Pool>,
     web::Path(id): web::Path<i32>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
+    auth::validate_access(request, &pool, Scope::CommentsRead).await?;
     let comment = comments::get_comment(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
     Ok(HttpResponse::Ok().json(comment))
 }
@@ -73,17 +261,26 @@ async fn update_comment(
     pool: web::Data<Pool>,
     web::Path(id): web::Path<i32>,
     data: web::Json<UpdateCommentData>,
+    settings: web::Data<Settings>,
+    events: web::Data<Broadcaster>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::CommentsModerate).await?;
     let mut comment = comments::get_comment(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
-    let parser = Parser::new(data.markdown.as_str());
-    let mut unsafe_html = String::new();
-    pulldown_cmark::html::push_html(&mut unsafe_html, parser);
+    if !data.website.is_empty() && !html::is_web_url(&data.website) {
+        Err(error::ErrorBadRequest("INVALID_WEBSITE"))?;
+    }
+    let before = json!(comment);
+    let was_public = events::is_public(&comment);
     comment.name = data.name.clone();
     comment.email = data.email.clone();
     comment.website = data.website.clone();
     comment.markdown = data.markdown.clone();
-    comment.html = ammonia::clean(&*unsafe_html);
+    let references = render::ThreadReferences::new(mentions::get_thread_commenters(&pool, comment.thread_id,
+        Some(id)).await?);
+    let rendered = render::render_comment(&data.markdown, &settings, &references);
+    comment.html = rendered.html;
+    comment.references = rendered.mentions.len() as i64;
     comment.status = data.status;
     comments::update_comment(&pool, id, UpdateComment {
         name: data.name.clone(),
@@ -93,6 +290,15 @@ async fn update_comment(
         html: comment.html.clone(),
         status: data.status,
     }).await?;
+    mentions::set_mentions(&pool, id, &rendered.mentions).await?;
+    if settings.attachments {
+        db::attachments::link_attachments(&pool, id, attachments::find_attachment_keys(&data.markdown)).await?;
+    }
+    log_action(&pool, &user, ip, AuditAction::UpdateComment, json!({
+        "before": before,
+        "after": comment,
+    })).await?;
+    events.comment_changed(&pool, &settings, &comment, was_public).await?;
     Ok(HttpResponse::Ok().json(comment))
 }
 
@@ -101,20 +307,188 @@ async fn delete_comment(
     request: web::HttpRequest,
     pool: web::Data<Pool>,
     web::Path(id): web::Path<i32>,
+    query: web::Query<DeleteQuery>,
+    settings: web::Data<Settings>,
+    events: web::Data<Broadcaster>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
-    comments::delete_comment(&pool, id).await?;
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::CommentsModerate).await?;
+    let comment = comments::get_comment(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    let permanent = query.permanent.unwrap_or(false);
+    if permanent {
+        comments::purge_comment(&pool, id).await?;
+        events.comment_purged(&comment);
+    } else {
+        comments::delete_comment(&pool, id).await?;
+        if let Some(deleted) = comments::get_comment(&pool, id).await? {
+            events.comment_changed(&pool, &settings, &deleted, events::is_public(&comment)).await?;
+        }
+    }
+    log_action(&pool, &user, ip, AuditAction::DeleteComment, json!({
+        "before": comment,
+        "permanent": permanent,
+    })).await?;
     Ok(HttpResponse::NoContent().body(""))
 }
 
+#[post("/admin/comments/{id:\\d+}/restore")]
+async fn restore_comment(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    web::Path(id): web::Path<i32>,
+    settings: web::Data<Settings>,
+    events: web::Data<Broadcaster>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::CommentsModerate).await?;
+    let mut comment = comments::get_comment(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    if comment.deleted_at.is_none() {
+        Err(error::ErrorBadRequest("NOT_DELETED"))?;
+    }
+    comments::restore_comment(&pool, id).await?;
+    comment.deleted_at = None;
+    log_action(&pool, &user, ip, AuditAction::RestoreComment, json!({ "after": comment })).await?;
+    events.comment_changed(&pool, &settings, &comment, false).await?;
+    Ok(HttpResponse::Ok().json(comment))
+}
+
+#[post("/admin/comments/{id:\\d+}/move")]
+async fn move_comment(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    web::Path(id): web::Path<i32>,
+    data: web::Json<MoveCommentData>,
+    settings: web::Data<Settings>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::CommentsModerate).await?;
+    let comment = comments::get_comment(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    threads::get_thread_by_id(&pool, data.thread_id).await?
+        .ok_or_else(|| error::ErrorBadRequest("THREAD_NOT_FOUND"))?;
+    let mut tx = pool.begin().await?;
+    let moved = comments::move_comment(&mut tx, id, data.thread_id).await?;
+    log_action_tx(&mut tx, &user, ip, AuditAction::MoveComment, json!({
+        "before": comment,
+        "thread_id": data.thread_id,
+        "ids": moved,
+    })).await?;
+    tx.commit().await?;
+    info!("Moved {} comments from thread {} to thread {}", moved.len(), comment.thread_id, data.thread_id);
+    let comment = comments::get_comment(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    Ok(HttpResponse::Ok().json(comment))
+}
+
+fn get_bulk_filter(ids: Option<Vec<i32>>, filter: Option<BulkFilterData>) -> BulkFilter {
+    match filter {
+        Some(filter) => BulkFilter {
+            ids,
+            status: filter.status,
+            thread_id: filter.thread_id,
+            ip: filter.ip,
+            email: filter.email,
+            since: filter.since,
+            until: filter.until,
+        },
+        None => BulkFilter {
+            ids,
+            status: None,
+            thread_id: None,
+            ip: None,
+            email: None,
+            since: None,
+            until: None,
+        },
+    }
+}
+
+#[post("/admin/comments/bulk")]
+async fn bulk_update_comments(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    data: web::Json<BulkData>,
+    settings: web::Data<Settings>,
+    events: web::Data<Broadcaster>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::CommentsModerate).await?;
+    let data = data.into_inner();
+    let filter = get_bulk_filter(data.ids, data.filter);
+    if filter.is_empty() {
+        Err(error::ErrorBadRequest("MISSING_FILTER"))?;
+    }
+    let action = match data.action {
+        BulkActionData::Approve => BulkAction::SetStatus(CommentStatus::Approved),
+        BulkActionData::Reject => BulkAction::SetStatus(CommentStatus::Rejected),
+        BulkActionData::Delete => BulkAction::Delete,
+        BulkActionData::Move => {
+            let thread_id = data.target_thread_id.ok_or_else(|| error::ErrorBadRequest("MISSING_TARGET_THREAD"))?;
+            threads::get_thread_by_id(&pool, thread_id).await?
+                .ok_or_else(|| error::ErrorBadRequest("THREAD_NOT_FOUND"))?;
+            BulkAction::Move(thread_id)
+        },
+    };
+    let publish = !matches!(action, BulkAction::Move(_));
+    let mut tx = pool.begin().await?;
+    let result = comments::bulk_update_comments(&mut tx, &filter, action).await?;
+    log_action_tx(&mut tx, &user, ip, AuditAction::BulkUpdateComments, json!({
+        "action": data.action,
+        "target_thread_id": data.target_thread_id,
+        "ids": result.ids,
+        "affected": result.affected,
+    })).await?;
+    tx.commit().await?;
+    info!("Bulk action {:?} applied to {} comments", data.action, result.ids.len());
+    if publish {
+        for comment in comments::get_comments_by_ids(&pool, &result.ids).await? {
+            events.comment_changed(&pool, &settings, &comment, result.approved_ids.contains(&comment.id)).await?;
+        }
+    }
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Maximum number of diffs included in the response of `POST /admin/rerender`.
+const MAX_RERENDER_DIFFS: usize = 100;
+
+/// Regenerates the HTML of all comments, or the ones matching the filter, from
+/// their markdown.
+#[post("/admin/rerender")]
+async fn rerender_comments(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    data: web::Json<RerenderData>,
+    settings: web::Data<Settings>,
+    events: web::Data<Broadcaster>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::CommentsModerate).await?;
+    let data = data.into_inner();
+    let filter = get_bulk_filter(data.ids, data.filter);
+    let result = rerender::rerender_comments(&pool, &settings, &filter, data.dry_run, MAX_RERENDER_DIFFS).await?;
+    info!("Rerendered {} comments, {} changed", result.checked, result.changed.len());
+    if !data.dry_run {
+        for changed in result.changed.iter() {
+            if let Some(comment) = comments::get_comment(&pool, changed.id).await? {
+                let was_public = events::is_public(&comment);
+                events.comment_changed(&pool, &settings, &comment, was_public).await?;
+            }
+        }
+        log_action(&pool, &user, ip, AuditAction::RerenderComments, json!({
+            "checked": result.checked,
+            "ids": result.changed.iter().map(|c| c.id).collect::<Vec<i32>>(),
+        })).await?;
+    }
+    Ok(HttpResponse::Ok().json(result))
+}
+
 #[get("/admin/threads")]
 async fn get_threads(
     request: web::HttpRequest,
     pool: web::Data<Pool>,
     query: web::Query<ThreadQuery>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
-    Ok(HttpResponse::Ok().json(threads::get_threads(&pool, 30, query.offset.unwrap_or(0)).await?))
+    auth::validate_access(request, &pool, Scope::ThreadsRead).await?;
+    Ok(HttpResponse::Ok().json(threads::get_threads(&pool, query.deleted.unwrap_or(false), 30,
+        query.offset.unwrap_or(0)).await?))
 }
 
 #[post("/admin/threads")]
@@ -122,9 +496,19 @@ async fn create_thread(
     request: web::HttpRequest,
     pool: web::Data<Pool>,
     data: web::Json<NewThread>,
+    settings: web::Data<Settings>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
-    Ok(HttpResponse::Ok().json(threads::create_thread(&pool, data.into_inner()).await?))
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::ThreadsWrite).await?;
+    let mut data = data.into_inner();
+    data.name = normalize_thread_name(&data.name, &settings);
+    if threads::get_thread_by_name(&pool, &data.name).await?.is_some() {
+        Err(error::ErrorBadRequest("THREAD_NAME_TAKEN"))?;
+    }
+    threads::delete_thread_alias_by_name(&pool, &data.name).await?;
+    let thread = threads::create_thread(&pool, data).await?;
+    log_action(&pool, &user, ip, AuditAction::CreateThread, json!({ "after": thread })).await?;
+    Ok(HttpResponse::Ok().json(thread))
 }
 
 #[get("/admin/threads/{id:\\d+}")]
@@ -133,7 +517,7 @@ async fn get_thread(
     pool: web::Data<Pool>,
     web::Path(id): web::Path<i32>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
+    auth::validate_access(request, &pool, Scope::ThreadsRead).await?;
     let thread = threads::get_thread_by_id(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
     Ok(HttpResponse::Ok().json(thread))
 }
@@ -144,11 +528,42 @@ async fn update_thread(
     pool: web::Data<Pool>,
     web::Path(id): web::Path<i32>,
     data: web::Json<UpdateThread>,
+    settings: web::Data<Settings>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::ThreadsWrite).await?;
     let mut thread = threads::get_thread_by_id(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
-    thread.title = data.title.clone();
-    threads::update_thread(&pool, id, data.into_inner()).await?;
+    let before = json!(thread);
+    let mut data = data.into_inner();
+    data.name = data.name.map(|name| normalize_thread_name(&name, &settings));
+    if let Some(name) = &data.name {
+        if name.is_empty() {
+            Err(error::ErrorBadRequest("MISSING_NAME"))?;
+        }
+        if *name != thread.name {
+            if threads::get_thread_by_name_or_alias(&pool, name).await?.filter(|t| t.id != id).is_some() {
+                Err(error::ErrorBadRequest("THREAD_NAME_TAKEN"))?;
+            }
+        }
+    }
+    if data.max_depth.flatten().map(|d| d < 0 || d > 6).unwrap_or(false) {
+        Err(error::ErrorBadRequest("INVALID_MAX_DEPTH"))?;
+    }
+    if data.close_after.flatten().map(|d| d < 0).unwrap_or(false) {
+        Err(error::ErrorBadRequest("INVALID_CLOSE_AFTER"))?;
+    }
+    let old_name = thread.name.clone();
+    data.apply(&mut thread);
+    threads::update_thread(&pool, id, data).await?;
+    if thread.name != old_name {
+        // Keep the old name working
+        threads::delete_thread_alias_by_name(&pool, &thread.name).await?;
+        threads::create_thread_alias(&pool, id, &old_name).await?;
+    }
+    log_action(&pool, &user, ip, AuditAction::UpdateThread, json!({
+        "before": before,
+        "after": thread,
+    })).await?;
     Ok(HttpResponse::Ok().json(thread))
 }
 
@@ -157,19 +572,161 @@ async fn delete_thread(
     request: web::HttpRequest,
     pool: web::Data<Pool>,
     web::Path(id): web::Path<i32>,
+    query: web::Query<DeleteQuery>,
+    settings: web::Data<Settings>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::ThreadsWrite).await?;
+    let thread = threads::get_thread_by_id(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    let permanent = query.permanent.unwrap_or(false);
+    if permanent {
+        threads::purge_thread(&pool, id).await?;
+    } else {
+        threads::delete_thread(&pool, id).await?;
+    }
+    log_action(&pool, &user, ip, AuditAction::DeleteThread, json!({
+        "before": thread,
+        "permanent": permanent,
+    })).await?;
+    Ok(HttpResponse::NoContent().body(""))
+}
+
+#[post("/admin/threads/{id:\\d+}/merge")]
+async fn merge_thread(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    web::Path(id): web::Path<i32>,
+    data: web::Json<MergeThreadData>,
+    settings: web::Data<Settings>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::ThreadsWrite).await?;
+    let thread = threads::get_thread_by_id(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    if data.target_thread_id == id {
+        Err(error::ErrorBadRequest("SAME_THREAD"))?;
+    }
+    threads::get_thread_by_id(&pool, data.target_thread_id).await?
+        .ok_or_else(|| error::ErrorBadRequest("THREAD_NOT_FOUND"))?;
+    let mut tx = pool.begin().await?;
+    let moved = threads::merge_threads(&mut tx, id, data.target_thread_id).await?;
+    log_action_tx(&mut tx, &user, ip, AuditAction::MergeThread, json!({
+        "before": thread,
+        "target_thread_id": data.target_thread_id,
+        "comments": moved,
+    })).await?;
+    tx.commit().await?;
+    info!("Merged thread '{}' (id: {}) into thread {}", thread.name, id, data.target_thread_id);
+    let target = threads::get_thread_by_id(&pool, data.target_thread_id).await?
+        .ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    Ok(HttpResponse::Ok().json(target))
+}
+
+#[post("/admin/threads/{id:\\d+}/refresh")]
+async fn refresh_thread(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    web::Path(id): web::Path<i32>,
+    settings: web::Data<Settings>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::ThreadsWrite).await?;
+    let mut thread = threads::get_thread_by_id(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    let before = json!(thread);
+    let url = match (&thread.url, &settings.thread_url) {
+        (Some(url), _) => url.clone(),
+        (None, Some(thread_url)) => thread_url.replace("%name%", &thread.name),
+        (None, None) => Err(error::ErrorBadRequest("MISSING_THREAD_URL"))?,
+    };
+    metadata::check_page_url(&url, &settings).await?;
+    let metadata = metadata::fetch_metadata(&url).await?
+        .ok_or_else(|| error::ErrorBadRequest("PAGE_NOT_FOUND"))?;
+    thread.title = metadata.title.or(thread.title);
+    thread.url = metadata.url.or(thread.url);
+    threads::update_thread_metadata(&pool, id, thread.title.clone(), thread.url.clone()).await?;
+    log_action(&pool, &user, ip, AuditAction::UpdateThread, json!({
+        "before": before,
+        "after": thread,
+    })).await?;
+    Ok(HttpResponse::Ok().json(thread))
+}
+
+#[get("/admin/threads/{id:\\d+}/aliases")]
+async fn get_thread_aliases(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    web::Path(id): web::Path<i32>,
+) -> actix_web::Result<HttpResponse> {
+    auth::validate_access(request, &pool, Scope::ThreadsRead).await?;
+    threads::get_thread_by_id(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    Ok(HttpResponse::Ok().json(threads::get_thread_aliases(&pool, id).await?))
+}
+
+#[post("/admin/threads/{id:\\d+}/aliases")]
+async fn create_thread_alias(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    web::Path(id): web::Path<i32>,
+    data: web::Json<NewThreadAliasData>,
+    settings: web::Data<Settings>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
-    threads::delete_thread(&pool, id).await?;
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::ThreadsWrite).await?;
+    threads::get_thread_by_id(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    if data.name.is_empty() {
+        Err(error::ErrorBadRequest("MISSING_NAME"))?;
+    }
+    let name = normalize_thread_name(&data.name, &settings);
+    if threads::get_thread_by_name_or_alias(&pool, &name).await?.is_some() {
+        Err(error::ErrorBadRequest("THREAD_NAME_TAKEN"))?;
+    }
+    let alias = threads::create_thread_alias(&pool, id, &name).await?;
+    log_action(&pool, &user, ip, AuditAction::CreateThreadAlias, json!({ "after": alias })).await?;
+    Ok(HttpResponse::Ok().json(alias))
+}
+
+#[delete("/admin/threads/{id:\\d+}/aliases/{alias_id:\\d+}")]
+async fn delete_thread_alias(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    web::Path((id, alias_id)): web::Path<(i32, i32)>,
+    settings: web::Data<Settings>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::ThreadsWrite).await?;
+    threads::delete_thread_alias(&pool, id, alias_id).await?;
+    log_action(&pool, &user, ip, AuditAction::DeleteThreadAlias, json!({
+        "thread_id": id,
+        "id": alias_id,
+    })).await?;
     Ok(HttpResponse::NoContent().body(""))
 }
 
+#[post("/admin/threads/{id:\\d+}/restore")]
+async fn restore_thread(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    web::Path(id): web::Path<i32>,
+    settings: web::Data<Settings>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::ThreadsWrite).await?;
+    let mut thread = threads::get_thread_by_id(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    if thread.deleted_at.is_none() {
+        Err(error::ErrorBadRequest("NOT_DELETED"))?;
+    }
+    threads::restore_thread(&pool, id).await?;
+    thread.deleted_at = None;
+    log_action(&pool, &user, ip, AuditAction::RestoreThread, json!({ "after": thread })).await?;
+    Ok(HttpResponse::Ok().json(thread))
+}
+
 #[get("/admin/users")]
 async fn get_users(
     request: web::HttpRequest,
     pool: web::Data<Pool>,
     query: web::Query<UserQuery>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
+    auth::validate_access(request, &pool, Scope::Users).await?;
     Ok(HttpResponse::Ok().json(users::get_users(&pool, 30, query.offset.unwrap_or(0)).await?))
 }
 
@@ -180,10 +737,13 @@ async fn create_user(
     data: web::Json<NewUser>,
     settings: web::Data<Settings>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
+    let ip = auth::get_client_ip(&request, &settings);
+    let actor = auth::validate_access(request, &pool, Scope::Users).await?;
     let mut user = data.into_inner();
     user.password = hash_password(&user.password, &settings)?;
-    Ok(HttpResponse::Ok().json(users::create_user(&pool, user).await?))
+    let user = users::create_user(&pool, user).await?;
+    log_action(&pool, &actor, ip, AuditAction::CreateUser, json!({ "after": user })).await?;
+    Ok(HttpResponse::Ok().json(user))
 }
 
 #[get("/admin/ussers/{id:\\d+}")]
@@ -192,7 +752,7 @@ async fn get_user(
     pool: web::Data<Pool>,
     web::Path(id): web::Path<i32>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
+    auth::validate_access(request, &pool, Scope::Users).await?;
     let user = users::get_user_by_id(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
     Ok(HttpResponse::Ok().json(user))
 }
@@ -205,19 +765,27 @@ async fn update_user(
     data: web::Json<UpdateUser>,
     settings: web::Data<Settings>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
+    let ip = auth::get_client_ip(&request, &settings);
+    let actor = auth::validate_access(request, &pool, Scope::Users).await?;
     let mut user = users::get_user_by_id(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    let before = json!(user);
     user.username = data.username.clone();
     user.name = data.name.clone();
     user.email = data.email.clone();
     user.website = data.website.clone();
     user.trusted = data.trusted;
-    user.admin = data.admin;
+    user.role = data.role;
     let mut update = data.into_inner();
+    let password_changed = update.password.is_some();
     if let Some(password) = update.password {
         update.password = Some(hash_password(&password, &settings)?);
     }
     users::update_user(&pool, id, update).await?;
+    log_action(&pool, &actor, ip, AuditAction::UpdateUser, json!({
+        "before": before,
+        "after": user,
+        "password_changed": password_changed,
+    })).await?;
     Ok(HttpResponse::Ok().json(user))
 }
 
@@ -226,9 +794,115 @@ async fn delete_user(
     request: web::HttpRequest,
     pool: web::Data<Pool>,
     web::Path(id): web::Path<i32>,
+    settings: web::Data<Settings>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
+    let ip = auth::get_client_ip(&request, &settings);
+    let actor = auth::validate_access(request, &pool, Scope::Users).await?;
+    let user = users::get_user_by_id(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
     users::delete_user(&pool, id).await?;
+    log_action(&pool, &actor, ip, AuditAction::DeleteUser, json!({ "before": user })).await?;
+    Ok(HttpResponse::NoContent().body(""))
+}
+
+#[get("/admin/tokens")]
+async fn get_tokens(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+) -> actix_web::Result<HttpResponse> {
+    let session = auth::validate_dashboard_session(request, &pool).await?;
+    Ok(HttpResponse::Ok().json(tokens::get_tokens_by_user_id(&pool, session.user.id).await?))
+}
+
+#[post("/admin/tokens")]
+async fn create_token(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    data: web::Json<NewToken>,
+    settings: web::Data<Settings>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let session = auth::validate_dashboard_session(request, &pool).await?;
+    if data.name.is_empty() {
+        Err(error::ErrorBadRequest("MISSING_NAME"))?;
+    }
+    let role_scopes = auth::get_role_scopes(session.user.role);
+    if data.scopes.iter().any(|scope| !role_scopes.contains(scope)) {
+        Err(error::ErrorForbidden("INSUFFICIENT_PRIVILEGES"))?;
+    }
+    let secret = auth::generate_token();
+    let token = tokens::create_token(&pool, session.user.id, &auth::hash_token(&secret), data.into_inner()).await?;
+    info!("Created API token '{}' (id: {}) for user {}", token.name, token.id, session.user.id);
+    log_action(&pool, &session.user, ip, AuditAction::CreateToken, json!({ "after": token })).await?;
+    Ok(HttpResponse::Ok().json(CreatedToken { token, secret }))
+}
+
+#[delete("/admin/tokens/{id:\\d+}")]
+async fn delete_token(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    web::Path(id): web::Path<i32>,
+    settings: web::Data<Settings>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let session = auth::validate_dashboard_session(request, &pool).await?;
+    tokens::delete_token(&pool, session.user.id, id).await?;
+    log_action(&pool, &session.user, ip, AuditAction::DeleteToken, json!({ "id": id })).await?;
+    Ok(HttpResponse::NoContent().body(""))
+}
+
+#[get("/admin/audit")]
+async fn get_audit_log(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    query: web::Query<AuditQuery>,
+) -> actix_web::Result<HttpResponse> {
+    auth::validate_access(request, &pool, Scope::AuditRead).await?;
+    let query = query.into_inner();
+    Ok(HttpResponse::Ok().json(audit::get_audit_entries(&pool, AuditFilter {
+        user_id: query.user_id,
+        action: query.action,
+        ip: query.ip,
+        since: query.since,
+        until: query.until,
+    }, 30, query.offset.unwrap_or(0)).await?))
+}
+
+#[get("/admin/attachments")]
+async fn get_attachments(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    query: web::Query<AttachmentQuery>,
+    storage: Option<web::Data<Storage>>,
+    settings: web::Data<Settings>,
+) -> actix_web::Result<HttpResponse> {
+    let base_url = get_base_url(&request, &settings);
+    auth::validate_access(request, &pool, Scope::CommentsRead).await?;
+    let storage = attachments::get_storage(storage)?;
+    let page = db::attachments::get_attachments(&pool, query.comment_id, 30, query.offset.unwrap_or(0)).await?;
+    Ok(HttpResponse::Ok().json(db::Page {
+        content: page.content.into_iter()
+            .map(|attachment| AttachmentInfo::new(attachment, &storage, &base_url))
+            .collect::<Vec<AttachmentInfo>>(),
+        remaining: page.remaining,
+        limit: page.limit,
+        next_cursor: None,
+    }))
+}
+
+#[delete("/admin/attachments/{id:\\d+}")]
+async fn delete_attachment(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    web::Path(id): web::Path<i32>,
+    settings: web::Data<Settings>,
+    storage: Option<web::Data<Storage>>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::CommentsModerate).await?;
+    let storage = attachments::get_storage(storage)?;
+    let attachment = db::attachments::get_attachment(&pool, id).await?.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    attachments::delete_attachment(&pool, &storage, &attachment).await?;
+    log_action(&pool, &user, ip, AuditAction::DeleteAttachment, json!({ "before": attachment })).await?;
     Ok(HttpResponse::NoContent().body(""))
 }
 
@@ -237,8 +911,10 @@ async fn import_comments(
     request: web::HttpRequest,
     pool: web::Data<Pool>,
     mut payload: Multipart,
+    settings: web::Data<Settings>,
 ) -> actix_web::Result<HttpResponse> {
-    auth::validate_admin_session(request, &pool).await?;
+    let ip = auth::get_client_ip(&request, &settings);
+    let user = auth::validate_access(request, &pool, Scope::Import).await?;
     while let Some(mut field) = payload.try_next().await? {
         let mut f = web::block(|| tempfile::tempfile()).await?;
         while let Some(chunk) = field.next().await {
@@ -248,7 +924,9 @@ async fn import_comments(
         f = web::block(move || f.seek(SeekFrom::Start(0)).map(|_| f)).await?;
         info!("Importing comments from XML file");
         let comments = web::block(move || import::read_xml_comments(f)).await?;
-        import::insert_imported_comments(&pool, comments).await?;
+        let thread_count = comments.len();
+        import::insert_imported_comments(&pool, &settings, comments).await?;
+        log_action(&pool, &user, ip.clone(), AuditAction::Import, json!({ "threads": thread_count })).await?;
     }
     Ok(HttpResponse::NoContent().body(""))
 }
@@ -256,19 +934,61 @@ async fn import_comments(
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(get_comments)
+        .service(search_comments)
         .service(get_comment)
         .service(update_comment)
         .service(delete_comment)
+        .service(restore_comment)
+        .service(move_comment)
+        .service(bulk_update_comments)
+        .service(rerender_comments)
         .service(get_threads)
         .service(create_thread)
         .service(get_thread)
         .service(update_thread)
         .service(delete_thread)
+        .service(merge_thread)
+        .service(refresh_thread)
+        .service(get_thread_aliases)
+        .service(create_thread_alias)
+        .service(delete_thread_alias)
+        .service(restore_thread)
         .service(get_users)
         .service(create_user)
         .service(get_user)
         .service(update_user)
         .service(delete_user)
+        .service(get_tokens)
+        .service(create_token)
+        .service(delete_token)
+        .service(get_attachments)
+        .service(delete_attachment)
+        .service(get_audit_log)
         .service(import_comments);
 }
 
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn parses_query_times_with_offsets() {
+        let expected = Utc.ymd(2021, 6, 1).and_hms(10, 0, 0);
+        assert_eq!(parse_query_time("2021-06-01T10:00:00Z"), Some(expected));
+        assert_eq!(parse_query_time("2021-06-01T12:00:00+02:00"), Some(expected));
+        assert_eq!(parse_query_time("2021-06-01T12:00:00 02:00"), Some(expected));
+        assert_eq!(parse_query_time("2021-06-01T08:00:00-02:00"), Some(expected));
+        assert_eq!(parse_query_time("2021-06-01"), None);
+    }
+
+    #[test]
+    fn decodes_audit_query() {
+        let query = web::Query::<AuditQuery>::from_query("since=2021-06-01T12:00:00+02:00").unwrap();
+        assert_eq!(query.since, Some(Utc.ymd(2021, 6, 1).and_hms(10, 0, 0)));
+        assert_eq!(query.until, None);
+        assert!(web::Query::<AuditQuery>::from_query("until=yesterday").is_err());
+    }
+}
@@ -0,0 +1,312 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Image attachments uploaded by commenters
+
+use std::io::Cursor;
+
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_web::{HttpResponse, error::{self, BlockingError}, get, post, web};
+use chrono::{Duration, Utc};
+use futures::{StreamExt, TryStreamExt};
+use image::{DynamicImage, ImageFormat, ImageOutputFormat, io::{Limits, Reader}};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{auth, db::{DbError, Pool, attachments::{self, Attachment, NewAttachment}}, html::get_base_url, settings::Settings, storage::Storage};
+
+/// Maximum width and height of uploaded images.
+const MAX_DIMENSION: u32 = 4096;
+
+/// Maximum memory used for decoding an image. A 4096x4096 RGBA image takes up
+/// 64 MiB.
+const MAX_ALLOC: u64 = 80 * 1024 * 1024;
+
+/// Uploaded files that haven't been attached to a comment within this time are
+/// deleted.
+const ORPHAN_RETENTION_HOURS: i64 = 24;
+
+static KEY_PATTERN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?:^|[^A-Za-z0-9_-])([A-Za-z0-9_-]{24}\.(?:png|jpg))").unwrap()
+});
+
+#[derive(Error, Debug)]
+enum ProcessError {
+    #[error("unsupported image type")]
+    UnsupportedType,
+    #[error("invalid image: {0}")]
+    InvalidImage(#[from] image::ImageError),
+}
+
+struct ProcessedImage {
+    data: Vec<u8>,
+    thumbnail: Vec<u8>,
+    content_type: &'static str,
+    extension: &'static str,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Serialize)]
+struct UploadedAttachment {
+    id: i32,
+    url: String,
+    thumbnail_url: String,
+    width: u32,
+    height: u32,
+    /// Markdown for inserting the image into a comment
+    markdown: String,
+}
+
+/// An attachment along with its public URLs as shown in the dashboard.
+#[derive(Serialize)]
+pub struct AttachmentInfo {
+    #[serde(flatten)]
+    pub attachment: Attachment,
+    pub url: String,
+    pub thumbnail_url: String,
+}
+
+impl AttachmentInfo {
+    pub fn new(attachment: Attachment, storage: &Storage, base_url: &str) -> AttachmentInfo {
+        AttachmentInfo {
+            url: storage.get_url(base_url, &attachment.key),
+            thumbnail_url: storage.get_url(base_url, &attachment.thumbnail_key),
+            attachment,
+        }
+    }
+}
+
+/// Reads the EXIF orientation of a JPEG image.
+fn get_orientation(data: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new().read_from_container(&mut Cursor::new(data)).ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?.value.get_uint(0)
+}
+
+fn apply_orientation(image: DynamicImage, orientation: Option<u32>) -> DynamicImage {
+    match orientation {
+        Some(2) => image.fliph(),
+        Some(3) => image.rotate180(),
+        Some(4) => image.flipv(),
+        Some(5) => image.rotate90().fliph(),
+        Some(6) => image.rotate90(),
+        Some(7) => image.rotate270().fliph(),
+        Some(8) => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Decodes and re-encodes an uploaded image, which removes EXIF and other
+/// metadata, and creates a thumbnail. JPEG images are stored as JPEG, all
+/// other supported types as PNG.
+fn process_image(data: &[u8], thumbnail_size: u32) -> Result<ProcessedImage, ProcessError> {
+    let format = image::guess_format(data).map_err(|_| ProcessError::UnsupportedType)?;
+    let (output_format, content_type, extension) = match format {
+        ImageFormat::Jpeg => (ImageOutputFormat::Jpeg(90), "image/jpeg", "jpg"),
+        ImageFormat::Png | ImageFormat::Gif | ImageFormat::WebP => (ImageOutputFormat::Png, "image/png", "png"),
+        _ => return Err(ProcessError::UnsupportedType),
+    };
+    let mut reader = Reader::with_format(Cursor::new(data), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_ALLOC);
+    reader.limits(limits);
+    let mut image = reader.decode()?;
+    if format == ImageFormat::Jpeg {
+        // The orientation is lost along with the rest of the metadata
+        image = apply_orientation(image, get_orientation(data));
+    }
+    let mut output = Vec::new();
+    image.write_to(&mut Cursor::new(&mut output), output_format.clone())?;
+    let mut thumbnail = Vec::new();
+    image.thumbnail(thumbnail_size, thumbnail_size).write_to(&mut Cursor::new(&mut thumbnail), output_format)?;
+    Ok(ProcessedImage {
+        data: output,
+        thumbnail,
+        content_type,
+        extension,
+        width: image.width(),
+        height: image.height(),
+    })
+}
+
+fn generate_key() -> String {
+    let bytes: [u8; 18] = rand::random();
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Finds the keys of uploaded files referenced in the markdown of a comment.
+pub fn find_attachment_keys(markdown: &str) -> Vec<String> {
+    KEY_PATTERN.captures_iter(markdown)
+        .filter_map(|captures| captures.get(1))
+        .map(|key| key.as_str().to_owned())
+        .collect()
+}
+
+/// Gets the storage, which is only available when attachments are enabled.
+pub fn get_storage(storage: Option<web::Data<Storage>>) -> actix_web::Result<web::Data<Storage>> {
+    storage.ok_or_else(|| error::ErrorForbidden("ATTACHMENTS_DISABLED"))
+}
+
+/// Deletes an attachment from storage and from the database.
+pub async fn delete_attachment(pool: &Pool, storage: &Storage, attachment: &Attachment) -> actix_web::Result<()> {
+    storage.delete(&attachment.key).await.map_err(error::ErrorInternalServerError)?;
+    storage.delete(&attachment.thumbnail_key).await.map_err(error::ErrorInternalServerError)?;
+    attachments::delete_attachment(pool, attachment.id).await?;
+    Ok(())
+}
+
+/// Deletes files that were never attached to a comment and files that belonged
+/// to comments that have since been permanently deleted. Files attached to
+/// rejected comments are deleted once the comments have been rejected for
+/// longer than the trash retention period.
+pub async fn cleanup_attachments(pool: &Pool, storage: &Storage, trash_retention: i64) -> Result<(), DbError> {
+    let rejected_before = if trash_retention > 0 { Some(Utc::now() - Duration::days(trash_retention)) } else { None };
+    let orphans = attachments::get_orphaned_attachments(pool, Utc::now() - Duration::hours(ORPHAN_RETENTION_HOURS),
+        rejected_before).await?;
+    for attachment in orphans.iter() {
+        if let Err(err) = delete_attachment(pool, storage, attachment).await {
+            warn!("Unable to delete attachment {}: {}", attachment.id, err);
+        }
+    }
+    if !orphans.is_empty() {
+        info!("Deleted {} orphaned attachments", orphans.len());
+    }
+    Ok(())
+}
+
+#[post("/attachments")]
+async fn upload_attachment(
+    request: web::HttpRequest,
+    mut payload: Multipart,
+    pool: web::Data<Pool>,
+    settings: web::Data<Settings>,
+    storage: Option<web::Data<Storage>>,
+) -> actix_web::Result<HttpResponse> {
+    let storage = get_storage(storage)?;
+    let ip = auth::get_client_ip(&request, &settings);
+    if settings.attachment_rate_limit > 0 {
+        let count = attachments::count_attachments_by_ip(&pool, &ip,
+            Utc::now() - Duration::minutes(settings.rate_limit_interval)).await?;
+        if count >= settings.attachment_rate_limit as i64 {
+            Err(error::ErrorTooManyRequests("TOO_MANY_ATTACHMENTS"))?;
+        }
+    }
+    let mut data = Vec::new();
+    if let Some(mut field) = payload.try_next().await? {
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk?;
+            if data.len() + chunk.len() > settings.attachment_max_size {
+                Err(error::ErrorPayloadTooLarge("ATTACHMENT_TOO_LARGE"))?;
+            }
+            data.extend_from_slice(&chunk);
+        }
+    }
+    if data.is_empty() {
+        Err(error::ErrorBadRequest("MISSING_FILE"))?;
+    }
+    let thumbnail_size = settings.attachment_thumbnail_size;
+    let image = web::block(move || process_image(&data, thumbnail_size)).await.map_err(|err| match err {
+        BlockingError::Error(ProcessError::UnsupportedType) => error::ErrorBadRequest("UNSUPPORTED_TYPE"),
+        BlockingError::Error(ProcessError::InvalidImage(_)) => error::ErrorBadRequest("INVALID_IMAGE"),
+        BlockingError::Canceled => error::ErrorInternalServerError("INTERNAL_ERROR"),
+    })?;
+    let name = generate_key();
+    let key = format!("{}.{}", name, image.extension);
+    let thumbnail_key = format!("{}-thumbnail.{}", name, image.extension);
+    let size = image.data.len() as i64;
+    storage.put(&key, image.content_type, image.data).await.map_err(error::ErrorInternalServerError)?;
+    storage.put(&thumbnail_key, image.content_type, image.thumbnail).await.map_err(error::ErrorInternalServerError)?;
+    let attachment = attachments::create_attachment(&pool, NewAttachment {
+        key,
+        thumbnail_key,
+        content_type: image.content_type.to_owned(),
+        size,
+        width: image.width as i32,
+        height: image.height as i32,
+        ip,
+    }).await?;
+    info!("Uploaded attachment {} ({} bytes)", attachment.key, size);
+    let info = AttachmentInfo::new(attachment, &storage, &get_base_url(&request, &settings));
+    // Large images are shown as a thumbnail linking to the full image
+    let markdown = if image.width > thumbnail_size || image.height > thumbnail_size {
+        format!("[![]({})]({})", info.thumbnail_url, info.url)
+    } else {
+        format!("![]({})", info.url)
+    };
+    Ok(HttpResponse::Ok().json(UploadedAttachment {
+        id: info.attachment.id,
+        url: info.url,
+        thumbnail_url: info.thumbnail_url,
+        width: image.width,
+        height: image.height,
+        markdown,
+    }))
+}
+
+/// Serves attachments from local storage.
+#[get("/attachments/{key}")]
+async fn get_attachment_file(
+    web::Path(key): web::Path<String>,
+    storage: Option<web::Data<Storage>>,
+) -> actix_web::Result<NamedFile> {
+    let storage = storage.ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    if key.starts_with(".") || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        Err(error::ErrorNotFound("NOT_FOUND"))?;
+    }
+    let path = storage.get_local_path(&key).ok_or_else(|| error::ErrorNotFound("NOT_FOUND"))?;
+    Ok(NamedFile::open(path).map_err(|_| error::ErrorNotFound("NOT_FOUND"))?)
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(upload_attachment)
+        .service(get_attachment_file);
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+
+    fn encode_png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn processes_png_with_thumbnail() {
+        let image = process_image(&encode_png(800, 200), 400).unwrap();
+        assert_eq!((image.width, image.height), (800, 200));
+        assert_eq!(image.content_type, "image/png");
+        let thumbnail = image::load_from_memory(&image.thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (400, 100));
+    }
+
+    #[test]
+    fn rejects_large_images() {
+        assert!(matches!(process_image(&encode_png(MAX_DIMENSION + 1, 1), 400), Err(ProcessError::InvalidImage(_))));
+    }
+
+    #[test]
+    fn rejects_unsupported_types() {
+        assert!(matches!(process_image(b"<svg></svg>", 400), Err(ProcessError::UnsupportedType)));
+    }
+
+    #[test]
+    fn finds_attachment_keys() {
+        assert_eq!(find_attachment_keys("![](https://example.com/attachments/abcdefghijklmnopqrstuvwx.png) \
+            [![](/attachments/ABCDEFGHIJKLMNOPQRSTUVWX-thumbnail.jpg)](/attachments/ABCDEFGHIJKLMNOPQRSTUVWX.jpg)"),
+            vec!["abcdefghijklmnopqrstuvwx.png", "ABCDEFGHIJKLMNOPQRSTUVWX.jpg"]);
+    }
+}
@@ -5,13 +5,15 @@
 
 //! Uncomment authentication handling
 
-use actix_web::{HttpMessage, HttpResponse, cookie::Cookie, delete, error, get, post, put, web};
+use actix_web::{HttpMessage, HttpResponse, cookie::Cookie, delete, error, get, http::header, post, put, web};
 use argonautica::{Hasher, Verifier};
 use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
 use log::{error, info};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::{db::{Pool, sessions::{self, Session}, users::{self, NewUser, User}}, settings::Settings};
+use crate::{db::{Pool, audit::{self, AuditAction, NewAuditEntry}, sessions::{self, Session}, tokens::{self, Scope}, users::{self, NewUser, Role, User}}, settings::Settings};
 
 #[derive(Debug, Deserialize)]
 pub struct Credentials {
@@ -34,7 +36,8 @@ pub struct SessionUser {
     pub email: String,
     pub website: String,
     pub trusted: bool,
-    pub admin: bool,
+    pub role: Role,
+    pub scopes: &'static [Scope],
 }
 
 
@@ -47,7 +50,8 @@ impl From<User> for SessionUser {
             email: user.email,
             website: user.website,
             trusted: user.trusted,
-            admin: user.admin,
+            role: user.role,
+            scopes: get_role_scopes(user.role),
         }
     }
 }
@@ -72,23 +76,114 @@ pub async fn validate_session(
     }
 }
 
-pub async fn validate_admin_session(
+/// Determines the IP address of the client, using the forwarding headers if
+/// Uncomment is configured to run behind a proxy.
+pub fn get_client_ip(request: &web::HttpRequest, settings: &Settings) -> String {
+    match settings.forwarded {
+        true => request.connection_info().realip_remote_addr().unwrap_or("").to_owned(),
+        false => request.peer_addr().map(|a| a.ip().to_string()).unwrap_or("".to_owned()),
+    }
+}
+
+/// Returns the scopes granted to users with the given role.
+pub fn get_role_scopes(role: Role) -> &'static [Scope] {
+    match role {
+        Role::User => &[],
+        Role::Moderator => &[Scope::CommentsRead, Scope::CommentsModerate, Scope::ThreadsRead],
+        Role::ThreadManager => &[Scope::CommentsRead, Scope::ThreadsRead, Scope::ThreadsWrite],
+        Role::Admin => &[
+            Scope::CommentsRead,
+            Scope::CommentsModerate,
+            Scope::ThreadsRead,
+            Scope::ThreadsWrite,
+            Scope::Import,
+            Scope::Users,
+            Scope::AuditRead,
+        ],
+    }
+}
+
+/// Validates a session belonging to a user with access to the dashboard.
+pub async fn validate_dashboard_session(
     request: web::HttpRequest,
     pool: &web::Data<Pool>,
 ) -> actix_web::Result<Session> {
     let session = validate_session(request, pool).await?;
-    if session.user.admin {
+    if session.user.role != Role::User {
         Ok(session)
     } else {
         Err(error::ErrorForbidden("INSUFFICIENT_PRIVILEGES"))
     }
 }
 
+/// Validates either an API token passed in the `Authorization` header or a
+/// session cookie. Other authorization schemes, e.g. basic auth added by a
+/// reverse proxy, are ignored. The user's role must grant the given scope, and
+/// tokens must additionally have been created with it.
+pub async fn validate_access(
+    request: web::HttpRequest,
+    pool: &web::Data<Pool>,
+    scope: Scope,
+) -> actix_web::Result<User> {
+    let token = request.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.to_owned());
+    let user = match token {
+        Some(token) => {
+            let token_user = tokens::get_token_user_by_hash(pool, &hash_token(&token)).await?
+                .ok_or_else(|| error::ErrorUnauthorized("INVALID_TOKEN"))?;
+            if !token_user.scopes.contains(&scope) {
+                info!("token {} is missing scope {}", token_user.id, scope);
+                Err(error::ErrorForbidden("INSUFFICIENT_SCOPE"))?;
+            }
+            tokens::touch_token(pool, token_user.id).await?;
+            token_user.user
+        },
+        None => validate_session(request, pool).await?.user,
+    };
+    if get_role_scopes(user.role).contains(&scope) {
+        Ok(user)
+    } else {
+        info!("user {} with role {} is missing scope {}", user.id, user.role, scope);
+        Err(error::ErrorForbidden("INSUFFICIENT_PRIVILEGES"))
+    }
+}
+
 pub fn generate_session_id() -> String {
     let bytes: [u8; 30] = rand::random();
     base64::encode(bytes)
 }
 
+pub fn generate_token() -> String {
+    let bytes: [u8; 30] = rand::random();
+    format!("uc_{}", base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+}
+
+/// API tokens are random and long enough that an unsalted SHA-256 hash is
+/// sufficient, which also allows looking them up by hash.
+pub fn hash_token(token: &str) -> String {
+    base64::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Creates a token that proves that the holder is the author of a comment.
+pub fn sign_author_token(comment_id: i32, settings: &Settings) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(settings.secret_key.as_bytes()).expect("valid key length");
+    mac.update(comment_id.to_string().as_bytes());
+    format!("{}.{}", comment_id, base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD))
+}
+
+/// Returns the comment id of a valid author token.
+pub fn verify_author_token(token: &str, settings: &Settings) -> Option<i32> {
+    let mut parts = token.splitn(2, ".");
+    let id = parts.next()?;
+    let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(settings.secret_key.as_bytes()).ok()?;
+    mac.update(id.as_bytes());
+    mac.verify_slice(&signature).ok()?;
+    id.parse().ok()
+}
+
 pub fn hash_password(password: &str, settings: &Settings) -> actix_web::Result<String> {
     Hasher::default()
         .configure_iterations(settings.argon2_iterations)
@@ -125,16 +220,21 @@ async fn get_auth(
 
 #[post("/auth")]
 async fn create_auth(
+    request: web::HttpRequest,
     data: web::Json<Credentials>,
     pool: web::Data<Pool>,
     settings: web::Data<Settings>,
 ) -> actix_web::Result<HttpResponse> {
-    let password = users::get_password_by_username(&pool, &data.username).await?
-        .ok_or_else(|| {
+    let ip = get_client_ip(&request, &settings);
+    let password = users::get_password_by_username(&pool, &data.username).await?;
+    let valid = match &password {
+        Some(password) => verify_password(&password.password, &data.password, &settings)?,
+        None => {
             info!("user not found: {}", data.username);
-            error::ErrorBadRequest("INVALID_CREDENTIALS")
-        })?;
-    if verify_password(&password.password, &data.password, &settings)? {
+            false
+        },
+    };
+    if let (true, Some(password)) = (valid, password) {
         let session_id = generate_session_id();
         let user = users::get_user_by_id(&pool, password.user_id).await?
             .ok_or_else(|| {
@@ -146,6 +246,13 @@ async fn create_auth(
             _ => 1,
         };
         sessions::create_session(&pool, &session_id, Utc::now() + Duration::hours(lifetime), user.id).await?;
+        audit::create_audit_entry(&pool, NewAuditEntry {
+            user_id: Some(user.id),
+            username: user.username.clone(),
+            action: AuditAction::Login,
+            ip,
+            details: serde_json::json!({}),
+        }).await?;
         Ok(HttpResponse::Ok()
             .cookie(Cookie::build("uncomment_session", session_id)
                 .max_age(time::Duration::hours(lifetime))
@@ -154,6 +261,13 @@ async fn create_auth(
             .json(SessionUser::from(user)))
     } else {
         info!("invalid password");
+        audit::create_audit_entry(&pool, NewAuditEntry {
+            user_id: None,
+            username: data.username.clone(),
+            action: AuditAction::LoginFailed,
+            ip,
+            details: serde_json::json!({}),
+        }).await?;
         Err(error::ErrorBadRequest("INVALID_CREDENTIALS"))
     }
 }
@@ -212,7 +326,7 @@ pub async fn install(pool: &Pool, settings: &Settings) -> actix_web::Result<()>
             email: "".to_owned(),
             website: "".to_owned(),
             trusted: true,
-            admin: true,
+            role: Role::Admin,
         }).await?;
     }
     Ok(())
@@ -223,3 +337,37 @@ pub async fn cleanup(pool: &Pool) -> actix_web::Result<()> {
     sessions::delete_expired_sessions(pool).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_signed_author_tokens() {
+        let settings = Settings::for_tests();
+        let token = sign_author_token(42, &settings);
+        assert!(token.starts_with("42."));
+        assert_eq!(verify_author_token(&token, &settings), Some(42));
+    }
+
+    #[test]
+    fn rejects_tampered_author_tokens() {
+        let settings = Settings::for_tests();
+        let token = sign_author_token(42, &settings);
+        let signature = token.strip_prefix("42.").unwrap();
+        assert_eq!(verify_author_token(&format!("43.{}", signature), &settings), None);
+        assert_eq!(verify_author_token(&format!("042.{}", signature), &settings), None);
+        assert_eq!(verify_author_token(&format!("42.{}", &signature[1..]), &settings), None);
+        assert_eq!(verify_author_token("42", &settings), None);
+        assert_eq!(verify_author_token("42.", &settings), None);
+        assert_eq!(verify_author_token("42.!!!", &settings), None);
+    }
+
+    #[test]
+    fn rejects_author_tokens_signed_with_another_key() {
+        let settings = Settings::for_tests();
+        let mut other = Settings::for_tests();
+        other.secret_key = format!("{}-other", settings.secret_key);
+        assert_eq!(verify_author_token(&sign_author_token(42, &other), &settings), None);
+    }
+}
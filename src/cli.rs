@@ -0,0 +1,326 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Command line interface
+
+use std::{fs::File, io::{self, BufRead, Write}, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use thiserror::Error;
+
+use crate::{auth::hash_password, db::{DbError, Pool, Transaction, audit::{self, AuditAction, NewAuditEntry}, comments::{self, BulkAction, BulkFilter, CommentFilter, CommentSort, CommentStatus}, users::{self, NewUser, convert_role}}, export::{self, ExportError, ExportFormat}, import::{self, ImportError}, rerender, settings::Settings};
+
+#[derive(Parser)]
+#[clap(version, about = "Uncomment comment server")]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the server (default)
+    Serve,
+    #[clap(flatten)]
+    Admin(AdminCommand),
+}
+
+/// Administration commands, i.e. every command except `serve`.
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// Create or update the database and exit. Migrations are also run
+    /// automatically before the server and every other command start
+    Migrate,
+    /// Create a new user, the password is read from standard input
+    CreateUser {
+        username: String,
+        /// User, Moderator, ThreadManager or Admin
+        #[clap(long, default_value = "Admin")]
+        role: String,
+        #[clap(long)]
+        name: Option<String>,
+        #[clap(long, default_value = "")]
+        email: String,
+    },
+    /// Change the password of a user, the password is read from standard input
+    ResetPassword {
+        username: String,
+    },
+    /// Import comments from a Disqus XML export
+    Import {
+        file: PathBuf,
+    },
+    /// Export the approved comments of all threads as JSON and HTML files
+    Export {
+        /// Output directory
+        dir: PathBuf,
+        /// Only export threads that have changed since this time (RFC 3339)
+        #[clap(long)]
+        since: Option<DateTime<Utc>>,
+        /// Which files to write for each thread
+        #[clap(long, arg_enum, default_value = "all")]
+        format: ExportFormat,
+        /// Public URL of the Uncomment server used by the comment form in the
+        /// HTML files, defaults to UNCOMMENT_BASE_URL
+        #[clap(long, default_value = "")]
+        base_url: String,
+    },
+    /// Approve comments
+    Approve {
+        #[clap(required = true)]
+        ids: Vec<i32>,
+    },
+    /// Reject comments
+    Reject {
+        #[clap(required = true)]
+        ids: Vec<i32>,
+    },
+    /// List comments awaiting moderation
+    ListPending {
+        #[clap(long, default_value = "50")]
+        limit: usize,
+    },
+    /// Delete all comments posted from an IP address
+    PurgeIp {
+        ip: String,
+        /// Delete the comments permanently instead of moving them to the trash
+        #[clap(long)]
+        permanent: bool,
+    },
+    /// Regenerate the HTML of comments from their markdown, e.g. after changing
+    /// the markdown or sanitization settings
+    Rerender {
+        /// Only rerender these comments
+        ids: Vec<i32>,
+        #[clap(long)]
+        thread_id: Option<i32>,
+        /// Only rerender comments posted since this time (RFC 3339)
+        #[clap(long)]
+        since: Option<DateTime<Utc>>,
+        /// Only rerender comments posted before this time (RFC 3339)
+        #[clap(long)]
+        until: Option<DateTime<Utc>>,
+        /// List the comments that would change without saving them
+        #[clap(long)]
+        dry_run: bool,
+        /// Show the changes to the HTML of each comment
+        #[clap(long)]
+        diff: bool,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum CliError {
+    #[error("database error: {0}")]
+    DbError(#[from] DbError),
+    #[error("export failed: {0}")]
+    ExportError(#[from] ExportError),
+    #[error("import failed: {0}")]
+    ImportError(#[from] ImportError),
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("invalid role: {0}")]
+    InvalidRole(String),
+    #[error("user not found: {0}")]
+    UserNotFound(String),
+    #[error("password cannot be empty")]
+    EmptyPassword,
+    #[error("unable to hash password")]
+    HashError,
+}
+
+fn read_password() -> Result<String, CliError> {
+    eprint!("Password: ");
+    io::stderr().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]).to_owned();
+    if password.is_empty() {
+        return Err(CliError::EmptyPassword);
+    }
+    Ok(password)
+}
+
+/// Adds an entry to the audit log for an action performed using the command
+/// line.
+async fn log_action(pool: &Pool, action: AuditAction, details: serde_json::Value) -> Result<(), DbError> {
+    audit::create_audit_entry(pool, NewAuditEntry {
+        user_id: None,
+        username: "cli".to_owned(),
+        action,
+        ip: "".to_owned(),
+        details,
+    }).await
+}
+
+async fn log_action_tx(tx: &mut Transaction, action: AuditAction, details: serde_json::Value) -> Result<(), DbError> {
+    audit::create_audit_entry_tx(tx, NewAuditEntry {
+        user_id: None,
+        username: "cli".to_owned(),
+        action,
+        ip: "".to_owned(),
+        details,
+    }).await
+}
+
+async fn set_status(pool: &Pool, ids: Vec<i32>, status: CommentStatus) -> Result<(), CliError> {
+    let filter = BulkFilter {
+        ids: Some(ids),
+        status: None,
+        thread_id: None,
+        ip: None,
+        email: None,
+        since: None,
+        until: None,
+    };
+    let mut tx = pool.begin().await?;
+    let result = comments::bulk_update_comments(&mut tx, &filter, BulkAction::SetStatus(status)).await?;
+    log_action_tx(&mut tx, AuditAction::BulkUpdateComments, json!({
+        "action": status,
+        "ids": result.ids,
+    })).await?;
+    tx.commit().await?;
+    println!("{} comments updated", result.affected);
+    Ok(())
+}
+
+/// Runs a command other than `serve`. Migrations have already been applied
+/// when the database was opened.
+pub async fn run(command: AdminCommand, pool: &Pool, settings: &Settings) -> Result<(), CliError> {
+    match command {
+        AdminCommand::Migrate => {
+            println!("Database is up to date");
+        },
+        AdminCommand::CreateUser { username, role, name, email } => {
+            let role = convert_role(&role).map_err(|_| CliError::InvalidRole(role))?;
+            let password = read_password()?;
+            let user = users::create_user(pool, NewUser {
+                name: name.unwrap_or_else(|| username.clone()),
+                username,
+                password: hash_password(&password, settings).map_err(|_| CliError::HashError)?,
+                email,
+                website: "".to_owned(),
+                trusted: true,
+                role,
+            }).await?;
+            log_action(pool, AuditAction::CreateUser, json!({ "after": user })).await?;
+            println!("Created user {} (id: {})", user.username, user.id);
+        },
+        AdminCommand::ResetPassword { username } => {
+            let user_id = users::get_password_by_username(pool, &username).await?
+                .ok_or_else(|| CliError::UserNotFound(username.clone()))?
+                .user_id;
+            let password = read_password()?;
+            users::change_password(pool, user_id, &hash_password(&password, settings)
+                .map_err(|_| CliError::HashError)?).await?;
+            log_action(pool, AuditAction::UpdateUser, json!({
+                "user_id": user_id,
+                "password_changed": true,
+            })).await?;
+            println!("Password changed for {}", username);
+        },
+        AdminCommand::Import { file } => {
+            let threads = import::read_xml_comments(File::open(file)?)?;
+            let thread_count = threads.len();
+            import::insert_imported_comments(pool, settings, threads).await?;
+            log_action(pool, AuditAction::Import, json!({ "threads": thread_count })).await?;
+            println!("Imported {} threads", thread_count);
+        },
+        AdminCommand::Export { dir, since, format, base_url } => {
+            let base_url = if base_url.is_empty() { settings.base_url.clone().unwrap_or_default() } else { base_url };
+            let count = export::export_threads(pool, settings, &dir, since, format,
+                base_url.trim_end_matches("/")).await?;
+            println!("Exported {} threads to {}", count, dir.display());
+        },
+        AdminCommand::Approve { ids } => set_status(pool, ids, CommentStatus::Approved).await?,
+        AdminCommand::Reject { ids } => set_status(pool, ids, CommentStatus::Rejected).await?,
+        AdminCommand::ListPending { limit } => {
+            let page = comments::get_comments(pool, &CommentFilter {
+                status: Some(CommentStatus::Pending),
+                parent_id: None,
+                thread_id: None,
+                ip: None,
+                email: None,
+                since: None,
+                until: None,
+                has_replies: None,
+                references: None,
+                referenced_by: None,
+                search: None,
+                deleted: false,
+            }, CommentSort::Created, true, limit, 0, None).await?;
+            for comment in page.content.iter() {
+                let content: String = comment.markdown.lines().next().unwrap_or("").chars().take(60).collect();
+                println!("{}\t{}\t{}\t{}\t{}\t{}", comment.id, comment.created, comment.thread_name, comment.name,
+                    comment.ip, content);
+            }
+            if let Some(remaining) = page.remaining.filter(|remaining| *remaining > 0) {
+                println!("... and {} more", remaining);
+            }
+        },
+        AdminCommand::PurgeIp { ip, permanent } => {
+            let filter = BulkFilter {
+                ids: None,
+                status: None,
+                thread_id: None,
+                ip: Some(ip.clone()),
+                email: None,
+                since: None,
+                until: None,
+            };
+            let details = |ids: &Vec<i32>| json!({
+                "action": if permanent { "Purge" } else { "Delete" },
+                "ip": ip,
+                "ids": ids,
+            });
+            let ids = if permanent {
+                // Comments that are already in the trash are purged as well
+                let ids = comments::get_comment_ids_by_ip(pool, &ip).await?;
+                for id in ids.iter() {
+                    comments::purge_comment(pool, *id).await?;
+                }
+                log_action(pool, AuditAction::BulkUpdateComments, details(&ids)).await?;
+                ids
+            } else {
+                let mut tx = pool.begin().await?;
+                let ids = comments::bulk_update_comments(&mut tx, &filter, BulkAction::Delete).await?.ids;
+                log_action_tx(&mut tx, AuditAction::BulkUpdateComments, details(&ids)).await?;
+                tx.commit().await?;
+                ids
+            };
+            println!("Deleted {} comments from {}", ids.len(), ip);
+        },
+        AdminCommand::Rerender { ids, thread_id, since, until, dry_run, diff } => {
+            let filter = BulkFilter {
+                ids: if ids.is_empty() { None } else { Some(ids) },
+                status: None,
+                thread_id,
+                ip: None,
+                email: None,
+                since,
+                until,
+            };
+            let max_diffs = if diff { usize::MAX } else { 0 };
+            let result = rerender::rerender_comments(pool, settings, &filter, dry_run, max_diffs).await?;
+            for changed in result.changed.iter() {
+                println!("Comment {} in thread {} changed", changed.id, changed.thread_id);
+                if let Some(diff) = &changed.diff {
+                    println!("{}", diff);
+                }
+            }
+            if !dry_run {
+                log_action(pool, AuditAction::RerenderComments, json!({
+                    "checked": result.checked,
+                    "ids": result.changed.iter().map(|c| c.id).collect::<Vec<i32>>(),
+                })).await?;
+            }
+            println!("{} of {} comments {}", result.changed.len(), result.checked,
+                if dry_run { "would change" } else { "updated" });
+        },
+    }
+    Ok(())
+}
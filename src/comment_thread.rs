@@ -0,0 +1,79 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Loading of public comment threads shared by the API, the server-side
+//! rendered pages and the static export
+
+use std::cmp;
+
+use chrono::{Duration, Utc};
+use serde::Serialize;
+
+use crate::{auth, db::{DbError, Pool, comments::{self, CommentStatus, PublicComment}, threads::{self, Thread}}, normalize::normalize_thread_name, settings::Settings};
+
+#[derive(Serialize)]
+pub struct CommentThread {
+    pub open: bool,
+    pub require_name: bool,
+    pub require_email: bool,
+    pub comments: Vec<PublicComment>,
+}
+
+pub fn get_max_depth(thread: &Thread, settings: &Settings) -> u8 {
+    thread.max_depth.map(|depth| cmp::max(0, cmp::min(6, depth)) as u8).unwrap_or(settings.max_depth)
+}
+
+/// Whether the thread has been closed automatically because of its age.
+pub async fn is_auto_closed(pool: &Pool, thread: &Thread, settings: &Settings) -> Result<bool, DbError> {
+    let days = thread.close_after.map(|days| days as i64).unwrap_or(settings.close_after);
+    if days <= 0 {
+        return Ok(false);
+    }
+    let start = if settings.close_after_first_comment {
+        comments::get_first_comment_created(pool, thread.id).await?
+    } else {
+        Some(thread.created)
+    };
+    Ok(start.map(|start| start + Duration::days(days) < Utc::now()).unwrap_or(false))
+}
+
+/// Loads the public comment tree of a thread. `author` is a comma-separated
+/// list of author tokens. The thread is `None` if it doesn't exist yet.
+pub async fn load_comment_thread(
+    pool: &Pool,
+    settings: &Settings,
+    name: &str,
+    newest_first: bool,
+    author: Option<&str>,
+) -> Result<(Option<Thread>, CommentThread), DbError> {
+    let thread = match threads::get_thread_by_name_or_alias(pool, &normalize_thread_name(name, settings)).await? {
+        Some(thread) => thread,
+        None => return Ok((None, CommentThread {
+            open: settings.auto_threads,
+            require_name: settings.require_name,
+            require_email: settings.require_email,
+            comments: vec![],
+        })),
+    };
+    let tombstones: Vec<CommentStatus> = settings.tombstones.split(",")
+        .filter_map(|status| comments::convert_comment_status(status.trim()).ok())
+        .collect();
+    let author_ids: Vec<i32> = match author {
+        Some(tokens) => tokens.split(",")
+            .take(100)
+            .filter_map(|token| auth::verify_author_token(token, settings))
+            .collect(),
+        None => vec![],
+    };
+    let comments = comments::get_comment_thread(pool, &thread.name, newest_first,
+        get_max_depth(&thread, settings), &tombstones, &author_ids).await?;
+    let comment_thread = CommentThread {
+        open: thread.is_open() && !is_auto_closed(pool, &thread, settings).await?,
+        require_name: thread.require_name.unwrap_or(settings.require_name),
+        require_email: thread.require_email.unwrap_or(settings.require_email),
+        comments,
+    };
+    Ok((Some(thread), comment_thread))
+}
@@ -0,0 +1,214 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! DB queries related to attachments
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use sea_query::{Expr, Iden, Order, Query, SelectStatement};
+use sqlx::Row;
+
+use crate::db::{DbError, DbRow, Page, Pool, comments::{CommentStatus, Comments}};
+
+use super::count_remaining;
+
+#[derive(Iden)]
+pub enum Attachments {
+    Table,
+    Id,
+    CommentId,
+    Key,
+    ThumbnailKey,
+    ContentType,
+    Size,
+    Width,
+    Height,
+    Ip,
+    Created,
+}
+
+#[derive(serde::Serialize)]
+pub struct Attachment {
+    pub id: i32,
+    pub comment_id: Option<i32>,
+    pub key: String,
+    pub thumbnail_key: String,
+    pub content_type: String,
+    pub size: i64,
+    pub width: i32,
+    pub height: i32,
+    pub ip: String,
+    pub created: String,
+    pub created_timestamp: i64,
+}
+
+pub struct NewAttachment {
+    pub key: String,
+    pub thumbnail_key: String,
+    pub content_type: String,
+    pub size: i64,
+    pub width: i32,
+    pub height: i32,
+    pub ip: String,
+}
+
+fn get_default_attachment_query() -> SelectStatement {
+    Query::select()
+        .columns(vec![
+            Attachments::Id,
+            Attachments::CommentId,
+            Attachments::Key,
+            Attachments::ThumbnailKey,
+            Attachments::ContentType,
+            Attachments::Size,
+            Attachments::Width,
+            Attachments::Height,
+            Attachments::Ip,
+            Attachments::Created,
+        ])
+        .from(Attachments::Table)
+        .to_owned()
+}
+
+fn read_attachment(row: &DbRow) -> Result<Attachment, DbError> {
+    let naive_created: NaiveDateTime = row.try_get(9)?;
+    let created: DateTime<Utc> = Utc.from_utc_datetime(&naive_created);
+    Ok(Attachment {
+        id: row.try_get(0)?,
+        comment_id: row.try_get(1)?,
+        key: row.try_get(2)?,
+        thumbnail_key: row.try_get(3)?,
+        content_type: row.try_get(4)?,
+        size: row.try_get(5)?,
+        width: row.try_get(6)?,
+        height: row.try_get(7)?,
+        ip: row.try_get(8)?,
+        created: created.to_rfc3339(),
+        created_timestamp: created.timestamp(),
+    })
+}
+
+async fn query_attachments(pool: &Pool, query: &SelectStatement) -> Result<Vec<Attachment>, DbError> {
+    let mut attachments = Vec::new();
+    for row in pool.select(query).await? {
+        attachments.push(read_attachment(&row)?);
+    }
+    Ok(attachments)
+}
+
+pub async fn create_attachment(pool: &Pool, data: NewAttachment) -> Result<Attachment, DbError> {
+    let created = Utc::now();
+    let id = pool.insert_returning(Query::insert().into_table(Attachments::Table)
+        .columns(vec![
+            Attachments::Key,
+            Attachments::ThumbnailKey,
+            Attachments::ContentType,
+            Attachments::Size,
+            Attachments::Width,
+            Attachments::Height,
+            Attachments::Ip,
+            Attachments::Created,
+        ])
+        .values_panic(vec![
+            data.key.as_str().into(),
+            data.thumbnail_key.as_str().into(),
+            data.content_type.as_str().into(),
+            data.size.into(),
+            data.width.into(),
+            data.height.into(),
+            data.ip.as_str().into(),
+            created.naive_utc().into(),
+        ])
+        .returning_col(Attachments::Id)).await?;
+    Ok(Attachment {
+        id,
+        comment_id: None,
+        key: data.key,
+        thumbnail_key: data.thumbnail_key,
+        content_type: data.content_type,
+        size: data.size,
+        width: data.width,
+        height: data.height,
+        ip: data.ip,
+        created: created.to_rfc3339(),
+        created_timestamp: created.timestamp(),
+    })
+}
+
+pub async fn get_attachment(pool: &Pool, id: i32) -> Result<Option<Attachment>, DbError> {
+    Ok(query_attachments(pool, get_default_attachment_query()
+        .and_where(Expr::col(Attachments::Id).eq(id))).await?.into_iter().next())
+}
+
+/// Gets attachments with the newest first.
+pub async fn get_attachments(
+    pool: &Pool,
+    comment_id: Option<i32>,
+    limit: usize,
+    offset: usize,
+) -> Result<Page<Attachment>, DbError> {
+    let mut query = get_default_attachment_query();
+    let mut count_query = Query::select()
+        .from(Attachments::Table)
+        .expr(Expr::count(Expr::col(Attachments::Id)))
+        .to_owned();
+    if let Some(comment_id) = comment_id {
+        query.and_where(Expr::col(Attachments::CommentId).eq(comment_id));
+        count_query.and_where(Expr::col(Attachments::CommentId).eq(comment_id));
+    }
+    query.order_by(Attachments::Id, Order::Desc)
+        .limit(limit as u64)
+        .offset(offset as u64);
+    let content = query_attachments(pool, &query).await?;
+    let remaining = count_remaining(pool, content.len(), limit, offset, &count_query).await?;
+    Ok(Page { content, remaining: Some(remaining), limit, next_cursor: None })
+}
+
+pub async fn count_attachments_by_ip(pool: &Pool, ip: &str, since: DateTime<Utc>) -> Result<i64, DbError> {
+    let result = pool.select_one(Query::select().from(Attachments::Table)
+        .expr(Expr::col(Attachments::Id).count())
+        .and_where(Expr::col(Attachments::Ip).eq(ip))
+        .and_where(Expr::col(Attachments::Created).gte(since.naive_utc())))
+        .await?;
+    Ok(result.try_get(0)?)
+}
+
+/// Attaches uploaded files that haven't been attached to a comment yet.
+pub async fn link_attachments(pool: &Pool, comment_id: i32, keys: Vec<String>) -> Result<u64, DbError> {
+    if keys.is_empty() {
+        return Ok(0);
+    }
+    pool.update(Query::update().table(Attachments::Table)
+        .value(Attachments::CommentId, comment_id.into())
+        .and_where(Expr::col(Attachments::Key).is_in(keys))
+        .and_where(Expr::col(Attachments::CommentId).is_null())).await
+}
+
+/// Gets attachments that were uploaded before the given time and that don't
+/// belong to a comment, either because the comment was never posted or
+/// because it has been permanently deleted. Attachments belonging to comments
+/// that were rejected before `rejected_before` are included as well.
+pub async fn get_orphaned_attachments(
+    pool: &Pool,
+    before: DateTime<Utc>,
+    rejected_before: Option<DateTime<Utc>>,
+) -> Result<Vec<Attachment>, DbError> {
+    let mut condition = Expr::col(Attachments::CommentId).is_null()
+        .and(Expr::col(Attachments::Created).lt(before.naive_utc()));
+    if let Some(rejected_before) = rejected_before {
+        condition = condition.or(Expr::col(Attachments::CommentId).in_subquery(Query::select()
+            .column(Comments::Id)
+            .from(Comments::Table)
+            .and_where(Expr::col(Comments::Status).eq(CommentStatus::Rejected))
+            .and_where(Expr::col(Comments::Modified).lt(rejected_before.naive_utc()))
+            .to_owned()));
+    }
+    query_attachments(pool, get_default_attachment_query().and_where(condition)).await
+}
+
+pub async fn delete_attachment(pool: &Pool, id: i32) -> Result<(), DbError> {
+    pool.delete(Query::delete().from_table(Attachments::Table)
+        .and_where(Expr::col(Attachments::Id).eq(id))).await?;
+    Ok(())
+}
@@ -0,0 +1,219 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! DB queries related to the audit log
+
+use std::fmt;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use sea_query::{Expr, Iden, InsertStatement, Order, Query, SelectStatement, Value};
+use sqlx::Row;
+
+use crate::db::{DbError, Page, Pool, Transaction};
+
+use super::count_remaining;
+
+#[derive(Iden)]
+pub enum AuditLog {
+    Table,
+    Id,
+    UserId,
+    Username,
+    Action,
+    Ip,
+    Details,
+    Created,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    UpdateComment,
+    DeleteComment,
+    RestoreComment,
+    MoveComment,
+    BulkUpdateComments,
+    CreateThread,
+    UpdateThread,
+    DeleteThread,
+    RestoreThread,
+    MergeThread,
+    CreateThreadAlias,
+    DeleteThreadAlias,
+    CreateUser,
+    UpdateUser,
+    DeleteUser,
+    CreateToken,
+    DeleteToken,
+    Import,
+    RerenderComments,
+    DeleteAttachment,
+}
+
+fn convert_audit_action(value: &str) -> Result<AuditAction, DbError> {
+    match value {
+        "Login" => Ok(AuditAction::Login),
+        "LoginFailed" => Ok(AuditAction::LoginFailed),
+        "UpdateComment" => Ok(AuditAction::UpdateComment),
+        "DeleteComment" => Ok(AuditAction::DeleteComment),
+        "RestoreComment" => Ok(AuditAction::RestoreComment),
+        "MoveComment" => Ok(AuditAction::MoveComment),
+        "BulkUpdateComments" => Ok(AuditAction::BulkUpdateComments),
+        "CreateThread" => Ok(AuditAction::CreateThread),
+        "UpdateThread" => Ok(AuditAction::UpdateThread),
+        "DeleteThread" => Ok(AuditAction::DeleteThread),
+        "RestoreThread" => Ok(AuditAction::RestoreThread),
+        "MergeThread" => Ok(AuditAction::MergeThread),
+        "CreateThreadAlias" => Ok(AuditAction::CreateThreadAlias),
+        "DeleteThreadAlias" => Ok(AuditAction::DeleteThreadAlias),
+        "CreateUser" => Ok(AuditAction::CreateUser),
+        "UpdateUser" => Ok(AuditAction::UpdateUser),
+        "DeleteUser" => Ok(AuditAction::DeleteUser),
+        "CreateToken" => Ok(AuditAction::CreateToken),
+        "DeleteToken" => Ok(AuditAction::DeleteToken),
+        "Import" => Ok(AuditAction::Import),
+        "RerenderComments" => Ok(AuditAction::RerenderComments),
+        "DeleteAttachment" => Ok(AuditAction::DeleteAttachment),
+        _ => Err(DbError::ColumnTypeError),
+    }
+}
+
+impl Into<Value> for AuditAction {
+    fn into(self) -> Value {
+        self.to_string().into()
+    }
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub user_id: Option<i32>,
+    pub username: String,
+    pub action: AuditAction,
+    pub ip: String,
+    pub details: serde_json::Value,
+    pub created: String,
+    pub created_timestamp: i64,
+}
+
+pub struct NewAuditEntry {
+    pub user_id: Option<i32>,
+    pub username: String,
+    pub action: AuditAction,
+    pub ip: String,
+    pub details: serde_json::Value,
+}
+
+pub struct AuditFilter {
+    pub user_id: Option<i32>,
+    pub action: Option<AuditAction>,
+    pub ip: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+fn apply_audit_filter(query: &mut SelectStatement, filter: &AuditFilter) {
+    if let Some(user_id) = filter.user_id {
+        query.and_where(Expr::col(AuditLog::UserId).eq(user_id));
+    }
+    if let Some(action) = filter.action {
+        query.and_where(Expr::col(AuditLog::Action).eq(action));
+    }
+    if let Some(ip) = &filter.ip {
+        query.and_where(Expr::col(AuditLog::Ip).eq(ip.as_str()));
+    }
+    if let Some(since) = filter.since {
+        query.and_where(Expr::col(AuditLog::Created).gte(since.naive_utc()));
+    }
+    if let Some(until) = filter.until {
+        query.and_where(Expr::col(AuditLog::Created).lt(until.naive_utc()));
+    }
+}
+
+fn get_insert_query(data: NewAuditEntry) -> InsertStatement {
+    Query::insert()
+        .into_table(AuditLog::Table)
+        .columns(vec![
+            AuditLog::UserId,
+            AuditLog::Username,
+            AuditLog::Action,
+            AuditLog::Ip,
+            AuditLog::Details,
+            AuditLog::Created,
+        ])
+        .values_panic(vec![
+            data.user_id.into(),
+            data.username.as_str().into(),
+            data.action.into(),
+            data.ip.as_str().into(),
+            data.details.to_string().into(),
+            Utc::now().naive_utc().into(),
+        ])
+        .to_owned()
+}
+
+pub async fn create_audit_entry(pool: &Pool, data: NewAuditEntry) -> Result<(), DbError> {
+    pool.insert(&get_insert_query(data)).await
+}
+
+/// Creates an audit entry as part of the transaction of the action it
+/// records.
+pub async fn create_audit_entry_tx(tx: &mut Transaction, data: NewAuditEntry) -> Result<(), DbError> {
+    tx.insert(&get_insert_query(data)).await
+}
+
+pub async fn get_audit_entries(
+    pool: &Pool,
+    filter: AuditFilter,
+    limit: usize,
+    offset: usize,
+) -> Result<Page<AuditEntry>, DbError> {
+    let mut query = Query::select().from(AuditLog::Table)
+        .columns(vec![
+            AuditLog::Id,
+            AuditLog::UserId,
+            AuditLog::Username,
+            AuditLog::Action,
+            AuditLog::Ip,
+            AuditLog::Details,
+            AuditLog::Created,
+        ])
+        .order_by(AuditLog::Created, Order::Desc)
+        .order_by(AuditLog::Id, Order::Desc)
+        .limit(limit as u64)
+        .offset(offset as u64)
+        .to_owned();
+    apply_audit_filter(&mut query, &filter);
+    let mut rows = pool.select(&query).await?.into_iter();
+    let mut content = Vec::new();
+    while let Some(row) = rows.next() {
+        let naive_created: NaiveDateTime = row.try_get(6)?;
+        let created: DateTime<Utc> = Utc.from_utc_datetime(&naive_created);
+        let details: String = row.try_get(5)?;
+        content.push(AuditEntry {
+            id: row.try_get(0)?,
+            user_id: row.try_get(1)?,
+            username: row.try_get(2)?,
+            action: convert_audit_action(row.try_get(3)?)?,
+            ip: row.try_get(4)?,
+            details: serde_json::from_str(&details).map_err(|_| DbError::ColumnTypeError)?,
+            created: created.to_rfc3339(),
+            created_timestamp: created.timestamp(),
+        });
+    }
+    let mut count_query = Query::select().from(AuditLog::Table)
+        .expr(Expr::col(AuditLog::Id).count())
+        .to_owned();
+    apply_audit_filter(&mut count_query, &filter);
+    let remaining = count_remaining(pool, content.len(), limit, offset, &count_query).await?;
+    Ok(Page { content, remaining: Some(remaining), limit, next_cursor: None })
+}
@@ -8,13 +8,13 @@
 use sea_query::{Alias, DynIden, Expr, Func, Iden, Order, Query, SelectStatement, SimpleExpr, Value};
 use sqlx::Row;
 
-use std::{cmp, collections::HashMap, fmt};
+use std::{cmp, collections::{HashMap, HashSet}, fmt};
 
 use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 
-use crate::db::{Page, Pool, DbError};
+use crate::db::{DbRow, Page, Pool, DbError, Transaction};
 
-use super::{count_remaining, threads::Threads};
+use super::{attachments::Attachments, count_remaining, mentions::Mentions, threads::Threads};
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq)]
 pub enum CommentStatus {
@@ -45,9 +45,12 @@ pub enum Comments {
     Markdown,
     Status,
     Created,
+    DeletedAt,
+    Modified,
+    Imported,
 }
 
-fn convert_comment_status(value: &str) -> Result<CommentStatus, DbError> {
+pub fn convert_comment_status(value: &str) -> Result<CommentStatus, DbError> {
     match value {
         "Pending" => Ok(CommentStatus::Pending),
         "Approved" => Ok(CommentStatus::Approved),
@@ -78,6 +81,8 @@ pub struct PublicComment {
     pub created: String,
     pub created_timestamp: i64,
     pub approved: bool,
+    pub deleted: bool,
+    pub hidden: bool,
     pub replies: Vec<PublicComment>,
 }
 
@@ -92,6 +97,13 @@ pub struct CommentPosition {
     pub level5_id: Option<i32>,
     pub level6_id: Option<i32>,
     pub status: CommentStatus,
+    pub deleted: bool,
+}
+
+impl CommentPosition {
+    pub fn levels(&self) -> [Option<i32>; 6] {
+        [self.level1_id, self.level2_id, self.level3_id, self.level4_id, self.level5_id, self.level6_id]
+    }
 }
 
 pub struct NewComment {
@@ -103,6 +115,9 @@ pub struct NewComment {
     pub markdown: String,
     pub status: CommentStatus,
     pub created: DateTime<Utc>,
+    /// Whether the comment was imported from Disqus, in which case `markdown`
+    /// contains the original HTML
+    pub imported: bool,
 }
 
 #[derive(serde::Serialize)]
@@ -121,12 +136,123 @@ pub struct PrivateComment {
     pub created: String,
     pub created_timestamp: i64,
     pub replies: i64,
+    pub deleted_at: Option<String>,
+    /// Number of comments mentioned or referred to by this comment
+    pub references: i64,
+    /// Number of comments that mention or refer to this comment
+    pub referenced_by: i64,
 }
 
-pub enum CommentFilter {
-    Status(CommentStatus),
-    Parent(i32),
-    Thread(i32),
+/// An approved comment along with the thread it was posted in.
+#[derive(serde::Serialize)]
+pub struct RecentComment {
+    pub id: i32,
+    pub thread_name: String,
+    pub thread_title: Option<String>,
+    pub thread_url: Option<String>,
+    pub parent_id: Option<i32>,
+    pub name: String,
+    pub website: String,
+    pub html: String,
+    pub created: String,
+    pub created_timestamp: i64,
+}
+
+pub struct CommentFilter {
+    pub status: Option<CommentStatus>,
+    pub parent_id: Option<i32>,
+    pub thread_id: Option<i32>,
+    pub ip: Option<String>,
+    pub email: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub has_replies: Option<bool>,
+    /// Only comments that mention or refer to the given comment
+    pub references: Option<i32>,
+    /// Only comments that are mentioned or referred to by the given comment
+    pub referenced_by: Option<i32>,
+    pub search: Option<String>,
+    pub deleted: bool,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum CommentSort {
+    Created,
+    Id,
+    Replies,
+}
+
+/// Position of the last comment on a page, used for keyset pagination. The
+/// key is the value of the sort column.
+pub struct CommentCursor {
+    pub key: i64,
+    pub id: i32,
+}
+
+impl CommentCursor {
+    fn from_comment(comment: &PrivateComment, sort: CommentSort) -> Option<CommentCursor> {
+        let key = match sort {
+            CommentSort::Created => DateTime::parse_from_rfc3339(&comment.created).ok()?.timestamp_nanos(),
+            CommentSort::Id => comment.id as i64,
+            CommentSort::Replies => comment.replies,
+        };
+        Some(CommentCursor { key, id: comment.id })
+    }
+
+    pub fn parse(cursor: &str) -> Option<CommentCursor> {
+        let decoded = String::from_utf8(base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?).ok()?;
+        let mut parts = decoded.splitn(2, ":");
+        Some(CommentCursor {
+            key: parts.next()?.parse().ok()?,
+            id: parts.next()?.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for CommentCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", base64::encode_config(format!("{}:{}", self.key, self.id), base64::URL_SAFE_NO_PAD))
+    }
+}
+
+pub struct BulkFilter {
+    pub ids: Option<Vec<i32>>,
+    pub status: Option<CommentStatus>,
+    pub thread_id: Option<i32>,
+    pub ip: Option<String>,
+    pub email: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl BulkFilter {
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_none() && self.status.is_none() && self.thread_id.is_none() && self.ip.is_none()
+            && self.email.is_none() && self.since.is_none() && self.until.is_none()
+    }
+}
+
+pub enum BulkAction {
+    SetStatus(CommentStatus),
+    Delete,
+    Move(i32),
+}
+
+#[derive(serde::Serialize)]
+pub struct BulkResult {
+    pub ids: Vec<i32>,
+    pub affected: u64,
+    /// Comments that were approved before the action was applied
+    #[serde(skip)]
+    pub approved_ids: HashSet<i32>,
+}
+
+/// The markdown of a comment and the HTML it was rendered as.
+pub struct CommentSource {
+    pub id: i32,
+    pub thread_id: i32,
+    pub markdown: String,
+    pub html: String,
 }
 
 pub struct UpdateComment {
@@ -146,6 +272,8 @@ pub async fn count_comments_by_thread(pool: &Pool, thread_names: Vec<&str>) -> R
         .inner_join(Threads::Table, Expr::tbl(Threads::Table, Threads::Id).equals(Comments::Table, Comments::ThreadId))
         .and_where(Expr::tbl(Threads::Table, Threads::Name).is_in(thread_names))
         .and_where(Expr::tbl(Comments::Table, Comments::Status).eq(CommentStatus::Approved))
+        .and_where(Expr::tbl(Comments::Table, Comments::DeletedAt).is_null())
+        .and_where(Expr::tbl(Threads::Table, Threads::DeletedAt).is_null())
         .group_by_col((Threads::Table, Threads::Name))).await?.into_iter();
     let mut result = HashMap::new();
     while let Some(row) = rows.next() {
@@ -168,6 +296,18 @@ fn build_comment_tree(comment: &mut PublicComment, replies: &HashMap<i32, Vec<Pu
     }
 }
 
+/// Removes hidden comments that don't have any visible replies.
+fn prune_hidden_comments(comments: Vec<PublicComment>) -> Vec<PublicComment> {
+    comments.into_iter().filter_map(|mut comment| {
+        comment.replies = prune_hidden_comments(comment.replies);
+        if comment.hidden && comment.replies.is_empty() {
+            None
+        } else {
+            Some(comment)
+        }
+    }).collect()
+}
+
 fn get_comment_order(newest_first: bool, max_depth: u8) -> Vec<(String, Order)> {
     let mut order = Vec::new();
     if newest_first {
@@ -210,13 +350,32 @@ fn get_parent_id(
     None
 }
 
+/// Gets the parent of a comment as shown in a thread with the given max depth,
+/// i.e. the closest visible ancestor.
+pub async fn get_visible_parent_id(pool: &Pool, id: i32, max_depth: u8) -> Result<Option<i32>, DbError> {
+    Ok(get_comment_position(pool, id).await?
+        .and_then(|position| get_parent_id(id, position.levels(), cmp::min(6, max_depth))))
+}
+
+/// Gets the approved comments of a thread as a tree. Deleted comments and
+/// comments with one of the `tombstones` statuses are included without their
+/// content if they have approved replies, so that the replies stay in place.
+/// Pending comments written by the current user (`author_ids`) are included
+/// with their content.
 pub async fn get_comment_thread(
     pool: &Pool,
     thread_name: &str,
     newest_first: bool,
     mut max_depth: u8,
+    tombstones: &[CommentStatus],
+    author_ids: &[i32],
 ) -> Result<Vec<PublicComment>, DbError> {
     max_depth = cmp::max(0, cmp::min(6, max_depth));
+    let mut statuses = vec![CommentStatus::Approved];
+    statuses.extend(tombstones.iter().filter(|status| **status != CommentStatus::Approved));
+    if !author_ids.is_empty() && !statuses.contains(&CommentStatus::Pending) {
+        statuses.push(CommentStatus::Pending);
+    }
     let rows = pool.select(Query::select().from(Comments::Table)
         .columns(vec![
             (Comments::Table, Comments::Id),
@@ -231,10 +390,13 @@ pub async fn get_comment_thread(
             (Comments::Table, Comments::Website),
             (Comments::Table, Comments::Html),
             (Comments::Table, Comments::Created),
+            (Comments::Table, Comments::DeletedAt),
+            (Comments::Table, Comments::Status),
         ])
         .inner_join(Threads::Table, Expr::tbl(Threads::Table, Threads::Id).equals(Comments::Table, Comments::ThreadId))
         .and_where(Expr::tbl(Threads::Table, Threads::Name).eq(thread_name))
-        .and_where(Expr::tbl(Comments::Table, Comments::Status).eq(CommentStatus::Approved))
+        .and_where(Expr::tbl(Threads::Table, Threads::DeletedAt).is_null())
+        .and_where(Expr::tbl(Comments::Table, Comments::Status).is_in(statuses))
         .order_by_customs(get_comment_order(newest_first, max_depth)))
         .await?;
     let mut root = Vec::new();
@@ -250,15 +412,28 @@ pub async fn get_comment_thread(
         let level5_id = row.try_get(6)?;
         let level6_id = row.try_get(7)?;
         let parent_id = get_parent_id(id, [level1_id, level2_id, level3_id, level4_id, level5_id, level6_id], max_depth);
+        let deleted_at: Option<NaiveDateTime> = row.try_get(12)?;
+        let deleted = deleted_at.is_some();
+        let status = convert_comment_status(row.try_get(13)?)?;
+        let approved = status == CommentStatus::Approved;
+        let own = status == CommentStatus::Pending && author_ids.contains(&id);
+        if !approved && !own && !tombstones.contains(&status) {
+            continue;
+        }
+        // The content of hidden comments is not exposed, they are only kept
+        // as placeholders for their replies
+        let hidden = deleted || !(approved || own);
         let comment = PublicComment {
             id,
             parent_id,
-            name: row.try_get(8)?,
-            website: row.try_get(9)?,
-            html: row.try_get(10)?,
+            name: if hidden { String::new() } else { row.try_get(8)? },
+            website: if hidden { String::new() } else { row.try_get(9)? },
+            html: if hidden { String::new() } else { row.try_get(10)? },
             created: created.to_rfc3339(),
             created_timestamp: created.timestamp(),
-            approved: true,
+            approved,
+            deleted,
+            hidden,
             replies: vec![],
         };
         match comment.parent_id {
@@ -278,11 +453,22 @@ pub async fn get_comment_thread(
         build_comment_tree(&mut comment, &replies);
         result.push(comment);
     }
-    Ok(result)
+    Ok(prune_hidden_comments(result))
 }
 
-pub async fn get_comment_position(pool: &Pool, id: i32) -> Result<Option<CommentPosition>, DbError> {
-    let result = pool.select_optional(Query::select().from(Comments::Table)
+fn get_level_column(level: usize) -> Comments {
+    match level {
+        1 => Comments::Level1Id,
+        2 => Comments::Level2Id,
+        3 => Comments::Level3Id,
+        4 => Comments::Level4Id,
+        5 => Comments::Level5Id,
+        _ => Comments::Level6Id,
+    }
+}
+
+fn get_position_query() -> SelectStatement {
+    Query::select().from(Comments::Table)
         .columns(vec![
             Comments::Id,
             Comments::ThreadId,
@@ -293,26 +479,90 @@ pub async fn get_comment_position(pool: &Pool, id: i32) -> Result<Option<Comment
             Comments::Level5Id,
             Comments::Level6Id,
             Comments::Status,
+            Comments::DeletedAt,
         ])
+        .to_owned()
+}
+
+fn read_comment_position(row: &DbRow) -> Result<CommentPosition, DbError> {
+    Ok(CommentPosition {
+        id: row.try_get(0)?,
+        thread_id: row.try_get(1)?,
+        level1_id: row.try_get(2)?,
+        level2_id: row.try_get(3)?,
+        level3_id: row.try_get(4)?,
+        level4_id: row.try_get(5)?,
+        level5_id: row.try_get(6)?,
+        level6_id: row.try_get(7)?,
+        status: convert_comment_status(row.try_get(8)?)?,
+        deleted: row.try_get::<Option<NaiveDateTime>, _>(9)?.is_some(),
+    })
+}
+
+pub async fn get_comment_position(pool: &Pool, id: i32) -> Result<Option<CommentPosition>, DbError> {
+    let result = pool.select_optional(get_position_query()
         .and_where(Expr::col(Comments::Id).eq(id)))
         .await?;
     if let Some(row) = result {
-        Ok(Some(CommentPosition {
-            id: row.try_get(0)?,
-            thread_id: row.try_get(1)?,
-            level1_id: row.try_get(2)?,
-            level2_id: row.try_get(3)?,
-            level3_id: row.try_get(4)?,
-            level4_id: row.try_get(5)?,
-            level5_id: row.try_get(6)?,
-            level6_id: row.try_get(7)?,
-            status: convert_comment_status(row.try_get(8)?)?,
-        }))
+        Ok(Some(read_comment_position(&row)?))
     } else {
         Ok(None)
     }
 }
 
+pub async fn get_first_comment_created(pool: &Pool, thread_id: i32) -> Result<Option<DateTime<Utc>>, DbError> {
+    let result = pool.select_one(Query::select().from(Comments::Table)
+        .expr(Expr::col(Comments::Created).min())
+        .and_where(Expr::col(Comments::ThreadId).eq(thread_id)))
+        .await?;
+    let created: Option<NaiveDateTime> = result.try_get(0)?;
+    Ok(created.map(|d| Utc.from_utc_datetime(&d)))
+}
+
+/// Gets the latest approved comments across all threads.
+pub async fn get_recent_comments(pool: &Pool, limit: usize) -> Result<Vec<RecentComment>, DbError> {
+    let mut rows = pool.select(Query::select()
+        .column((Comments::Table, Comments::Id))
+        .columns(vec![
+            (Threads::Table, Threads::Name),
+            (Threads::Table, Threads::Title),
+            (Threads::Table, Threads::Url),
+        ])
+        .columns(vec![
+            (Comments::Table, Comments::ParentId),
+            (Comments::Table, Comments::Name),
+            (Comments::Table, Comments::Website),
+            (Comments::Table, Comments::Html),
+            (Comments::Table, Comments::Created),
+        ])
+        .from(Comments::Table)
+        .inner_join(Threads::Table, Expr::tbl(Threads::Table, Threads::Id).equals(Comments::Table, Comments::ThreadId))
+        .and_where(Expr::tbl(Comments::Table, Comments::Status).eq(CommentStatus::Approved))
+        .and_where(Expr::tbl(Comments::Table, Comments::DeletedAt).is_null())
+        .and_where(Expr::tbl(Threads::Table, Threads::DeletedAt).is_null())
+        .order_by((Comments::Table, Comments::Created), Order::Desc)
+        .order_by((Comments::Table, Comments::Id), Order::Desc)
+        .limit(limit as u64)).await?.into_iter();
+    let mut content = Vec::new();
+    while let Some(row) = rows.next() {
+        let naive_created: NaiveDateTime = row.try_get(8)?;
+        let created: DateTime<Utc> = Utc.from_utc_datetime(&naive_created);
+        content.push(RecentComment {
+            id: row.try_get(0)?,
+            thread_name: row.try_get(1)?,
+            thread_title: row.try_get(2)?,
+            thread_url: row.try_get(3)?,
+            parent_id: row.try_get(4)?,
+            name: row.try_get(5)?,
+            website: row.try_get(6)?,
+            html: row.try_get(7)?,
+            created: created.to_rfc3339(),
+            created_timestamp: created.timestamp(),
+        });
+    }
+    Ok(content)
+}
+
 pub async fn count_comments_by_ip(pool: &Pool, ip: &str, since: DateTime<Utc>) -> Result<i64, DbError> {
     let result = pool.select_one(Query::select().from(Comments::Table)
         .expr(Expr::col(Comments::Id).count())
@@ -322,6 +572,21 @@ pub async fn count_comments_by_ip(pool: &Pool, ip: &str, since: DateTime<Utc>) -
     Ok(result.try_get(0)?)
 }
 
+/// Computes the parent id and level columns of a comment posted as a reply to
+/// `parent`. Replies to comments at the maximum level are attached to the
+/// comment at the maximum level instead.
+fn get_child_levels(parent: Option<&CommentPosition>, id: i32) -> (Option<i32>, [Option<i32>; 6]) {
+    let parent = match parent {
+        Some(parent) => parent,
+        None => return (None, [Some(id), None, None, None, None, None]),
+    };
+    let mut levels = parent.levels();
+    if let Some(free) = levels.iter().position(|level| level.is_none()) {
+        levels[free] = Some(id);
+    }
+    (Some(parent.level6_id.unwrap_or(parent.id)), levels)
+}
+
 pub async fn insert_comment(
     pool: &Pool,
     thread_id: i32,
@@ -339,6 +604,8 @@ pub async fn insert_comment(
             Comments::Markdown,
             Comments::Status,
             Comments::Created,
+            Comments::Modified,
+            Comments::Imported,
         ])
         .values_panic(vec![
             thread_id.into(),
@@ -350,20 +617,12 @@ pub async fn insert_comment(
             data.markdown.as_str().into(),
             data.status.into(),
             data.created.naive_utc().into(),
+            data.created.naive_utc().into(),
+            data.imported.into(),
         ])
         .returning_col(Comments::Id)).await?;
-    let parent_id = parent.map(|p| p.level6_id.unwrap_or(p.id));
-    let level1 = parent.map(|p| p.level1_id).flatten().unwrap_or(id);
-    let level2 = parent.map(|p| p.level2_id
-        .or_else(|| p.level1_id.map(|_| id))).flatten();
-    let level3 = parent.map(|p| p.level3_id
-        .or_else(|| p.level2_id.map(|_| id))).flatten();
-    let level4 = parent.map(|p| p.level4_id
-        .or_else(|| p.level3_id.map(|_| id))).flatten();
-    let level5 = parent.map(|p| p.level5_id
-        .or_else(|| p.level4_id.map(|_| id))).flatten();
-    let level6 = parent.map(|p| p.level6_id
-        .or_else(|| p.level5_id.map(|_| id))).flatten();
+    let (parent_id, [level1, level2, level3, level4, level5, level6]) = get_child_levels(parent, id);
+    let level1 = level1.unwrap_or(id);
     let mut update = Query::update();
     update.table(Comments::Table);
     update.value(Comments::Level1Id, level1.into());
@@ -398,6 +657,7 @@ pub async fn insert_comment(
         level5_id: level5,
         level6_id: level6,
         status: data.status,
+        deleted: false,
     })
 }
 
@@ -421,6 +681,8 @@ pub async fn post_comment(
         created: data.created.to_rfc3339(),
         created_timestamp: data.created.timestamp(),
         approved: data.status == CommentStatus::Approved,
+        deleted: false,
+        hidden: false,
         replies: vec![],
     })
 }
@@ -450,6 +712,17 @@ fn get_default_comment_query() -> SelectStatement {
                     .and_where(Expr::tbl(Comments::Table, Comments::Id)
                         .equals(nested.clone(), Comments::ParentId))
                     .to_owned())))
+        .column((Comments::Table, Comments::DeletedAt))
+        .expr(SimpleExpr::SubQuery(Box::new(Query::select()
+                    .expr(Func::count(Expr::col(Mentions::Id)))
+                    .from(Mentions::Table)
+                    .and_where(Expr::tbl(Mentions::Table, Mentions::CommentId).equals(Comments::Table, Comments::Id))
+                    .to_owned())))
+        .expr(SimpleExpr::SubQuery(Box::new(Query::select()
+                    .expr(Func::count(Expr::col(Mentions::Id)))
+                    .from(Mentions::Table)
+                    .and_where(Expr::tbl(Mentions::Table, Mentions::TargetId).equals(Comments::Table, Comments::Id))
+                    .to_owned())))
         .inner_join(Threads::Table, Expr::tbl(Threads::Table, Threads::Id).equals(Comments::Table, Comments::ThreadId))
         .to_owned()
 }
@@ -478,40 +751,159 @@ async fn query_comments(
             created: created.to_rfc3339(),
             created_timestamp: created.timestamp(),
             replies: row.try_get(12)?,
+            deleted_at: row.try_get::<Option<NaiveDateTime>, _>(13)?.map(|d| Utc.from_utc_datetime(&d).to_rfc3339()),
+            references: row.try_get(14)?,
+            referenced_by: row.try_get(15)?,
         });
     }
     Ok(content)
 }
 
-pub async fn get_comments(pool: &Pool, filter: CommentFilter, asc: bool, limit: usize, offset: usize) -> Result<Page<PrivateComment>, DbError> {
+/// Converts a search query into an FTS5 query matching all the terms.
+#[cfg(not(feature = "postgres"))]
+fn get_search_condition(query: &str) -> SimpleExpr {
+    let terms: Vec<String> = query.split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace("\"", "\"\"")))
+        .collect();
+    Expr::cust_with_values("comments.id in (select rowid from comments_fts where comments_fts match ?)",
+        vec![terms.join(" ")])
+}
+
+#[cfg(feature = "postgres")]
+fn get_search_condition(query: &str) -> SimpleExpr {
+    Expr::cust_with_values("comments.search @@ plainto_tsquery('simple', ?)", vec![query])
+}
+
+const REPLIES_EXPR: &str = "(select count(r.id) from comments r where r.parent_id = comments.id)";
+
+fn apply_comment_filter(query: &mut SelectStatement, filter: &CommentFilter) {
+    if let Some(status) = filter.status {
+        query.and_where(Expr::tbl(Comments::Table, Comments::Status).eq(status));
+    }
+    if let Some(parent_id) = filter.parent_id {
+        query.and_where(Expr::tbl(Comments::Table, Comments::ParentId).eq(parent_id));
+    }
+    if let Some(thread_id) = filter.thread_id {
+        query.and_where(Expr::tbl(Comments::Table, Comments::ThreadId).eq(thread_id));
+    }
+    if let Some(ip) = &filter.ip {
+        query.and_where(Expr::tbl(Comments::Table, Comments::Ip).eq(ip.as_str()));
+    }
+    if let Some(email) = &filter.email {
+        query.and_where(Expr::tbl(Comments::Table, Comments::Email).eq(email.as_str()));
+    }
+    if let Some(since) = filter.since {
+        query.and_where(Expr::tbl(Comments::Table, Comments::Created).gte(since.naive_utc()));
+    }
+    if let Some(until) = filter.until {
+        query.and_where(Expr::tbl(Comments::Table, Comments::Created).lt(until.naive_utc()));
+    }
+    match filter.has_replies {
+        Some(true) => {
+            query.and_where(Expr::cust(&format!("{} > 0", REPLIES_EXPR)));
+        },
+        Some(false) => {
+            query.and_where(Expr::cust(&format!("{} = 0", REPLIES_EXPR)));
+        },
+        None => {},
+    }
+    if let Some(target_id) = filter.references {
+        query.and_where(Expr::tbl(Comments::Table, Comments::Id).in_subquery(Query::select()
+            .column(Mentions::CommentId)
+            .from(Mentions::Table)
+            .and_where(Expr::col(Mentions::TargetId).eq(target_id))
+            .to_owned()));
+    }
+    if let Some(comment_id) = filter.referenced_by {
+        query.and_where(Expr::tbl(Comments::Table, Comments::Id).in_subquery(Query::select()
+            .column(Mentions::TargetId)
+            .from(Mentions::Table)
+            .and_where(Expr::col(Mentions::CommentId).eq(comment_id))
+            .to_owned()));
+    }
+    if let Some(search) = &filter.search {
+        query.and_where(get_search_condition(search));
+    }
+    if filter.deleted {
+        query.and_where(Expr::tbl(Comments::Table, Comments::DeletedAt).is_not_null());
+    } else {
+        query.and_where(Expr::tbl(Comments::Table, Comments::DeletedAt).is_null());
+    }
+}
+
+fn apply_comment_cursor(query: &mut SelectStatement, sort: CommentSort, asc: bool, cursor: &CommentCursor) {
+    let op = if asc { ">" } else { "<" };
+    match sort {
+        CommentSort::Created => {
+            let created = NaiveDateTime::from_timestamp(cursor.key.div_euclid(1_000_000_000),
+                cursor.key.rem_euclid(1_000_000_000) as u32);
+            query.and_where(Expr::cust_with_values(
+                &format!("(comments.created {} ? or (comments.created = ? and comments.id {} ?))", op, op),
+                vec![Value::from(created), Value::from(created), Value::from(cursor.id)]));
+        },
+        CommentSort::Id => {
+            query.and_where(Expr::cust_with_values(&format!("comments.id {} ?", op), vec![cursor.id]));
+        },
+        CommentSort::Replies => {
+            query.and_where(Expr::cust_with_values(
+                &format!("({} {} ? or ({} = ? and comments.id {} ?))", REPLIES_EXPR, op, REPLIES_EXPR, op),
+                vec![Value::from(cursor.key), Value::from(cursor.key), Value::from(cursor.id)]));
+        },
+    }
+}
+
+/// Gets a page of comments matching the filter. If a cursor is given the page
+/// starts after the comment it points to and the offset is ignored.
+pub async fn get_comments(
+    pool: &Pool,
+    filter: &CommentFilter,
+    sort: CommentSort,
+    asc: bool,
+    limit: usize,
+    offset: usize,
+    cursor: Option<&CommentCursor>,
+) -> Result<Page<PrivateComment>, DbError> {
     let mut query = get_default_comment_query();
-    match filter {
-        CommentFilter::Status(status) => {
-            query.and_where(Expr::tbl(Comments::Table, Comments::Status).eq(status));
+    apply_comment_filter(&mut query, filter);
+    let order = if asc { Order::Asc } else { Order::Desc };
+    match sort {
+        CommentSort::Created => {
+            query.order_by((Comments::Table, Comments::Created), order.clone());
         },
-        CommentFilter::Parent(parent_id) => {
-            query.and_where(Expr::tbl(Comments::Table, Comments::ParentId).eq(parent_id));
+        CommentSort::Id => {},
+        CommentSort::Replies => {
+            query.order_by_customs(vec![(REPLIES_EXPR.to_owned(), order.clone())]);
         },
-        CommentFilter::Thread(thread_id) => {
-            query.and_where(Expr::tbl(Comments::Table, Comments::ThreadId).eq(thread_id));
+    }
+    query.order_by((Comments::Table, Comments::Id), order);
+    let (content, remaining, more) = match cursor {
+        Some(cursor) => {
+            // Fetch one extra comment to find out whether there is a next
+            // page instead of counting the remaining comments
+            apply_comment_cursor(&mut query, sort, asc, cursor);
+            query.limit(limit as u64 + 1);
+            let mut content = query_comments(pool, &query).await?;
+            let more = content.len() > limit;
+            content.truncate(limit);
+            (content, None, more)
+        },
+        None => {
+            query.limit(limit as u64).offset(offset as u64);
+            let content = query_comments(pool, &query).await?;
+            let mut count_query = Query::select()
+                .from(Comments::Table)
+                .expr(Expr::tbl(Comments::Table, Comments::Id).count())
+                .to_owned();
+            apply_comment_filter(&mut count_query, filter);
+            let remaining = count_remaining(pool, content.len(), limit, offset, &count_query).await?;
+            (content, Some(remaining), remaining > 0)
         },
     };
-    if asc {
-        query.order_by((Comments::Table, Comments::Created), sea_query::Order::Asc);
-    } else {
-        query.order_by((Comments::Table, Comments::Created), sea_query::Order::Desc);
+    let next_cursor = match content.last() {
+        Some(last) if more => CommentCursor::from_comment(last, sort).map(|c| c.to_string()),
+        _ => None,
     };
-    query.limit(limit as u64).offset(offset as u64);
-    let content = query_comments(pool, &query).await?;
-    let remaining = count_remaining(pool, content.len(), limit, offset, Query::select()
-        .from(Comments::Table)
-        .expr(Expr::col(Comments::Id).count())
-        .and_where(match filter {
-            CommentFilter::Status(status) => Expr::col(Comments::Status).eq(status),
-            CommentFilter::Parent(parent_id) => Expr::col(Comments::ParentId).eq(parent_id),
-            CommentFilter::Thread(thread_id) => Expr::col(Comments::ThreadId).eq(thread_id),
-        })).await?;
-    Ok(Page { content, remaining, limit })
+    Ok(Page { content, remaining, limit, next_cursor })
 }
 
 pub async fn get_comment(pool: &Pool, id: i32) -> Result<Option<PrivateComment>, DbError> {
@@ -519,6 +911,17 @@ pub async fn get_comment(pool: &Pool, id: i32) -> Result<Option<PrivateComment>,
             .and_where(Expr::tbl(Comments::Table, Comments::Id).eq(id))).await?.into_iter().next())
 }
 
+/// Gets the comments with the given ids ordered by id.
+pub async fn get_comments_by_ids(pool: &Pool, ids: &[i32]) -> Result<Vec<PrivateComment>, DbError> {
+    let mut result = Vec::with_capacity(ids.len());
+    for chunk in ids.chunks(BULK_CHUNK_SIZE) {
+        result.extend(query_comments(pool, get_default_comment_query()
+                .and_where(Expr::tbl(Comments::Table, Comments::Id).is_in(chunk.to_vec()))
+                .order_by((Comments::Table, Comments::Id), Order::Asc)).await?);
+    }
+    Ok(result)
+}
+
 pub async fn update_comment(pool: &Pool, id: i32, data: UpdateComment) -> Result<(), DbError> {
     pool.update(Query::update().table(Comments::Table)
         .value(Comments::Name, data.name.into())
@@ -527,13 +930,304 @@ pub async fn update_comment(pool: &Pool, id: i32, data: UpdateComment) -> Result
         .value(Comments::Markdown, data.markdown.into())
         .value(Comments::Html, data.html.into())
         .value(Comments::Status, data.status.into())
+        .value(Comments::Modified, Utc::now().naive_utc().into())
+        .and_where(Expr::col(Comments::Id).eq(id))).await?;
+    Ok(())
+}
+
+/// Gets a batch of comments matching the filter with an id greater than
+/// `after_id` ordered by id. Imported comments are skipped since they don't
+/// have any markdown.
+pub async fn get_comment_sources(
+    pool: &Pool,
+    filter: &BulkFilter,
+    after_id: i32,
+    limit: usize,
+) -> Result<Vec<CommentSource>, DbError> {
+    let mut query = Query::select();
+    query.columns(vec![Comments::Id, Comments::ThreadId, Comments::Markdown, Comments::Html])
+        .from(Comments::Table)
+        .and_where(Expr::col(Comments::Id).gt(after_id))
+        .and_where(Expr::col(Comments::Imported).eq(false))
+        .order_by(Comments::Id, Order::Asc)
+        .limit(limit as u64);
+    apply_bulk_filter(&mut query, filter);
+    let mut sources = Vec::new();
+    for row in pool.select(&query).await? {
+        sources.push(CommentSource {
+            id: row.try_get(0)?,
+            thread_id: row.try_get(1)?,
+            markdown: row.try_get(2)?,
+            html: row.try_get(3)?,
+        });
+    }
+    Ok(sources)
+}
+
+pub async fn update_comment_html(pool: &Pool, id: i32, html: &str) -> Result<(), DbError> {
+    pool.update(Query::update().table(Comments::Table)
+        .value(Comments::Html, html.into())
+        .value(Comments::Modified, Utc::now().naive_utc().into())
         .and_where(Expr::col(Comments::Id).eq(id))).await?;
     Ok(())
 }
 
+/// Moves a comment to the trash.
 pub async fn delete_comment(pool: &Pool, id: i32) -> Result<(), DbError> {
+    let now = Utc::now().naive_utc();
+    pool.update(Query::update().table(Comments::Table)
+        .value(Comments::DeletedAt, now.into())
+        .value(Comments::Modified, now.into())
+        .and_where(Expr::col(Comments::Id).eq(id))).await?;
+    Ok(())
+}
+
+pub async fn restore_comment(pool: &Pool, id: i32) -> Result<(), DbError> {
+    pool.update(Query::update().table(Comments::Table)
+        .value(Comments::DeletedAt, Value::Null)
+        .value(Comments::Modified, Utc::now().naive_utc().into())
+        .and_where(Expr::col(Comments::Id).eq(id))).await?;
+    Ok(())
+}
+
+/// Gets the ids of all comments posted from an IP address, including comments
+/// in the trash.
+pub async fn get_comment_ids_by_ip(pool: &Pool, ip: &str) -> Result<Vec<i32>, DbError> {
+    let mut ids = Vec::new();
+    for row in pool.select(Query::select()
+        .column(Comments::Id)
+        .from(Comments::Table)
+        .and_where(Expr::col(Comments::Ip).eq(ip))
+        .order_by(Comments::Id, Order::Asc)).await? {
+        ids.push(row.try_get(0)?);
+    }
+    Ok(ids)
+}
+
+/// Permanently deletes a comment.
+pub async fn purge_comment(pool: &Pool, id: i32) -> Result<(), DbError> {
+    touch_threads(pool, Expr::col(Comments::Id).eq(id)).await?;
     pool.delete(Query::delete().from_table(Comments::Table)
         .and_where(Expr::col(Comments::Id).eq(id))).await?;
     Ok(())
 }
 
+/// Permanently deletes comments that were moved to the trash before the given
+/// time. Comments that still have replies are kept as empty placeholders so
+/// that the replies aren't orphaned. Returns the number of deleted and
+/// emptied comments.
+pub async fn purge_deleted_comments(pool: &Pool, before: DateTime<Utc>) -> Result<u64, DbError> {
+    let not_empty = Expr::cust("(comments.name <> '' or comments.email <> '' or comments.website <> '' \
+        or comments.ip <> '' or comments.markdown <> '' or comments.html <> '')");
+    touch_threads(pool, Expr::col(Comments::DeletedAt).lt(before.naive_utc()).and(not_empty.clone())).await?;
+    let deleted = pool.delete(Query::delete().from_table(Comments::Table)
+        .and_where(Expr::col(Comments::DeletedAt).lt(before.naive_utc()))
+        .and_where(Expr::cust("not exists (select r.id from comments r where r.parent_id = comments.id)")))
+        .await?;
+    let remaining = Query::select()
+        .column(Comments::Id)
+        .from(Comments::Table)
+        .and_where(Expr::col(Comments::DeletedAt).lt(before.naive_utc()))
+        .to_owned();
+    pool.delete(Query::delete().from_table(Mentions::Table)
+        .and_where(Expr::col(Mentions::CommentId).in_subquery(remaining.clone()))).await?;
+    // Unlinked attachments are deleted along with other orphaned attachments
+    pool.update(Query::update().table(Attachments::Table)
+        .value(Attachments::CommentId, Value::Null)
+        .and_where(Expr::col(Attachments::CommentId).in_subquery(remaining))).await?;
+    let emptied = pool.update(Query::update().table(Comments::Table)
+        .value(Comments::Name, "".into())
+        .value(Comments::Email, "".into())
+        .value(Comments::Website, "".into())
+        .value(Comments::Ip, "".into())
+        .value(Comments::Markdown, "".into())
+        .value(Comments::Html, "".into())
+        .value(Comments::Modified, Utc::now().naive_utc().into())
+        .and_where(Expr::col(Comments::DeletedAt).lt(before.naive_utc()))
+        .and_where(not_empty)).await?;
+    Ok(deleted + emptied)
+}
+
+/// Updates the modification time of threads containing comments matching the
+/// condition, so that permanently deleted comments are picked up by
+/// incremental exports.
+async fn touch_threads(pool: &Pool, condition: SimpleExpr) -> Result<u64, DbError> {
+    pool.update(Query::update().table(Threads::Table)
+        .value(Threads::Modified, Utc::now().naive_utc().into())
+        .and_where(Expr::col(Threads::Id).in_subquery(Query::select()
+            .column(Comments::ThreadId)
+            .from(Comments::Table)
+            .and_where(condition)
+            .to_owned()))).await
+}
+
+fn apply_bulk_filter(query: &mut SelectStatement, filter: &BulkFilter) {
+    if let Some(ids) = &filter.ids {
+        query.and_where(Expr::col(Comments::Id).is_in(ids.clone()));
+    }
+    if let Some(status) = filter.status {
+        query.and_where(Expr::col(Comments::Status).eq(status));
+    }
+    if let Some(thread_id) = filter.thread_id {
+        query.and_where(Expr::col(Comments::ThreadId).eq(thread_id));
+    }
+    if let Some(ip) = &filter.ip {
+        query.and_where(Expr::col(Comments::Ip).eq(ip.as_str()));
+    }
+    if let Some(email) = &filter.email {
+        query.and_where(Expr::col(Comments::Email).eq(email.as_str()));
+    }
+    if let Some(since) = filter.since {
+        query.and_where(Expr::col(Comments::Created).gte(since.naive_utc()));
+    }
+    if let Some(until) = filter.until {
+        query.and_where(Expr::col(Comments::Created).lt(until.naive_utc()));
+    }
+    query.and_where(Expr::col(Comments::DeletedAt).is_null());
+}
+
+/// Moves a comment and all of its replies to the root level of a thread.
+/// Returns the ids of all the moved comments.
+pub async fn move_comment(tx: &mut Transaction, id: i32, thread_id: i32) -> Result<Vec<i32>, DbError> {
+    let root = match tx.select(get_position_query()
+        .and_where(Expr::col(Comments::Id).eq(id))).await?.first() {
+        Some(row) => read_comment_position(row)?,
+        None => return Ok(vec![]),
+    };
+    let mut query = get_position_query();
+    query.column(Comments::ParentId)
+        .and_where(Expr::col(Comments::ThreadId).eq(root.thread_id))
+        .order_by(Comments::Id, Order::Asc);
+    // Replies can only be found via the level columns if the comment is not
+    // nested deeper than the maximum level
+    match root.levels().iter().position(|level| *level == Some(id)) {
+        Some(depth) => query.and_where(Expr::col(get_level_column(depth + 1)).eq(id)),
+        None => query.and_where(Expr::col(Comments::Id).eq(id)),
+    };
+    // Replies are placed below their moved parents as if they were posted
+    // again, so replies that were attached to a comment at the maximum level
+    // get their own level if there is room for it. Parents always have lower
+    // ids than their replies.
+    let mut positions: HashMap<i32, CommentPosition> = HashMap::new();
+    let mut moved = Vec::new();
+    for row in tx.select(&query).await? {
+        let position = read_comment_position(&row)?;
+        let parent = if position.id == id {
+            None
+        } else {
+            row.try_get::<Option<i32>, _>(10)?.and_then(|parent_id| positions.get(&parent_id))
+        };
+        let (parent_id, levels) = get_child_levels(parent, position.id);
+        let mut update = Query::update();
+        update.table(Comments::Table);
+        update.value(Comments::ThreadId, thread_id.into());
+        update.value(Comments::ParentId, parent_id.into());
+        update.value(Comments::Modified, Utc::now().naive_utc().into());
+        for (i, level) in levels.iter().enumerate() {
+            update.value(get_level_column(i + 1), (*level).into());
+        }
+        tx.update(update.and_where(Expr::col(Comments::Id).eq(position.id))).await?;
+        moved.push(position.id);
+        positions.insert(position.id, CommentPosition {
+            id: position.id,
+            thread_id,
+            level1_id: levels[0],
+            level2_id: levels[1],
+            level3_id: levels[2],
+            level4_id: levels[3],
+            level5_id: levels[4],
+            level6_id: levels[5],
+            status: position.status,
+            deleted: position.deleted,
+        });
+    }
+    Ok(moved)
+}
+
+/// Maximum number of ids in a single `IN (...)` condition, large bulk actions
+/// are split into several statements to stay below the parameter limits of
+/// the database.
+pub const BULK_CHUNK_SIZE: usize = 500;
+
+/// Applies an action to all comments matching the filter.
+pub async fn bulk_update_comments(tx: &mut Transaction, filter: &BulkFilter, action: BulkAction) -> Result<BulkResult, DbError> {
+    let mut query = Query::select()
+        .columns(vec![Comments::Id, Comments::Status])
+        .from(Comments::Table)
+        .order_by(Comments::Id, Order::Asc)
+        .to_owned();
+    apply_bulk_filter(&mut query, filter);
+    let mut ids: Vec<i32> = Vec::new();
+    let mut approved_ids = HashSet::new();
+    for row in tx.select(&query).await? {
+        let id = row.try_get(0)?;
+        ids.push(id);
+        if convert_comment_status(row.try_get(1)?)? == CommentStatus::Approved {
+            approved_ids.insert(id);
+        }
+    }
+    let mut affected = 0;
+    match action {
+        BulkAction::SetStatus(status) => for chunk in ids.chunks(BULK_CHUNK_SIZE) {
+            affected += tx.update(Query::update().table(Comments::Table)
+                .value(Comments::Status, status.into())
+                .value(Comments::Modified, Utc::now().naive_utc().into())
+                .and_where(Expr::col(Comments::Id).is_in(chunk.to_vec()))).await?;
+        },
+        BulkAction::Delete => for chunk in ids.chunks(BULK_CHUNK_SIZE) {
+            affected += tx.update(Query::update().table(Comments::Table)
+                .value(Comments::DeletedAt, Utc::now().naive_utc().into())
+                .value(Comments::Modified, Utc::now().naive_utc().into())
+                .and_where(Expr::col(Comments::Id).is_in(chunk.to_vec()))).await?;
+        },
+        BulkAction::Move(thread_id) => {
+            let mut moved = HashSet::new();
+            for id in ids.iter() {
+                if moved.contains(id) {
+                    continue;
+                }
+                moved.extend(move_comment(tx, *id, thread_id).await?);
+            }
+            affected = moved.len() as u64;
+        },
+    }
+    Ok(BulkResult { ids, affected, approved_ids })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(id: i32, levels: [Option<i32>; 6]) -> CommentPosition {
+        CommentPosition {
+            id,
+            thread_id: 1,
+            level1_id: levels[0],
+            level2_id: levels[1],
+            level3_id: levels[2],
+            level4_id: levels[3],
+            level5_id: levels[4],
+            level6_id: levels[5],
+            status: CommentStatus::Approved,
+            deleted: false,
+        }
+    }
+
+    #[test]
+    fn places_root_comments_at_first_level() {
+        assert_eq!(get_child_levels(None, 5), (None, [Some(5), None, None, None, None, None]));
+    }
+
+    #[test]
+    fn places_replies_below_parent() {
+        let parent = position(2, [Some(1), Some(2), None, None, None, None]);
+        assert_eq!(get_child_levels(Some(&parent), 3), (Some(2), [Some(1), Some(2), Some(3), None, None, None]));
+    }
+
+    #[test]
+    fn attaches_replies_beyond_max_level_to_last_level() {
+        let levels = [Some(1), Some(2), Some(3), Some(4), Some(5), Some(6)];
+        assert_eq!(get_child_levels(Some(&position(6, levels)), 7), (Some(6), levels));
+        assert_eq!(get_child_levels(Some(&position(7, levels)), 8), (Some(6), levels));
+    }
+}
@@ -0,0 +1,89 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! DB queries related to mentions of and references to other comments
+
+use std::fmt;
+
+use sea_query::{Expr, Iden, Order, Query, Value};
+use sqlx::Row;
+
+use crate::db::{DbError, Pool, comments::{CommentStatus, Comments}};
+
+#[derive(Iden)]
+pub enum Mentions {
+    Table,
+    Id,
+    CommentId,
+    TargetId,
+    Kind,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum MentionKind {
+    /// `@name`, the target is the latest earlier comment by that commenter
+    Mention,
+    /// `#c123`
+    Reference,
+}
+
+impl Into<Value> for MentionKind {
+    fn into(self) -> Value {
+        self.to_string().into()
+    }
+}
+
+impl fmt::Display for MentionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NewMention {
+    pub target_id: i32,
+    pub kind: MentionKind,
+}
+
+/// Gets the id and name of the approved comments in a thread in the order they
+/// were posted. If `before` is set, only comments posted before that comment
+/// are included.
+pub async fn get_thread_commenters(
+    pool: &Pool,
+    thread_id: i32,
+    before: Option<i32>,
+) -> Result<Vec<(i32, String)>, DbError> {
+    let mut query = Query::select();
+    query.columns(vec![Comments::Id, Comments::Name])
+        .from(Comments::Table)
+        .and_where(Expr::col(Comments::ThreadId).eq(thread_id))
+        .and_where(Expr::col(Comments::Status).eq(CommentStatus::Approved))
+        .and_where(Expr::col(Comments::DeletedAt).is_null())
+        .order_by(Comments::Id, Order::Asc);
+    if let Some(before) = before {
+        query.and_where(Expr::col(Comments::Id).lt(before));
+    }
+    let mut commenters = Vec::new();
+    for row in pool.select(&query).await? {
+        commenters.push((row.try_get(0)?, row.try_get(1)?));
+    }
+    Ok(commenters)
+}
+
+/// Replaces the mentions and references of a comment.
+pub async fn set_mentions(pool: &Pool, comment_id: i32, mentions: &[NewMention]) -> Result<(), DbError> {
+    pool.delete(Query::delete().from_table(Mentions::Table)
+        .and_where(Expr::col(Mentions::CommentId).eq(comment_id))).await?;
+    for mention in mentions {
+        pool.insert(Query::insert().into_table(Mentions::Table)
+            .columns(vec![Mentions::CommentId, Mentions::TargetId, Mentions::Kind])
+            .values_panic(vec![
+                comment_id.into(),
+                mention.target_id.into(),
+                mention.kind.into(),
+            ])).await?;
+    }
+    Ok(())
+}
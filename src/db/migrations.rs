@@ -5,11 +5,11 @@
 
 //! Minimal migration system
 
-use sea_query::{ColumnDef, ForeignKey, ForeignKeyAction, SchemaBuilder, Table};
+use sea_query::{ColumnDef, ForeignKey, ForeignKeyAction, Index, SchemaBuilder, Table};
 
-use crate::db::{comments::Comments, sessions::Sessions, users::Users};
+use crate::db::{attachments::Attachments, audit::AuditLog, comments::Comments, mentions::Mentions, sessions::Sessions, tokens::Tokens, users::Users};
 
-use super::threads::Threads;
+use super::threads::{ThreadAliases, Threads};
 
 pub static MIGRATIONS: &'static [(&'static str, fn(&dyn SchemaBuilder) -> Vec<String>)] = &[
     ("V1_Init", |builder| {
@@ -75,4 +75,236 @@ pub static MIGRATIONS: &'static [(&'static str, fn(&dyn SchemaBuilder) -> Vec<St
                 .build_any(builder),
         ]
     }),
+    ("V2_Tokens", |builder| {
+        vec![
+            Table::create()
+                .table(Tokens::Table)
+                .col(ColumnDef::new(Tokens::Id).integer().auto_increment().primary_key())
+                .col(ColumnDef::new(Tokens::UserId).integer().not_null())
+                .col(ColumnDef::new(Tokens::Name).string().not_null())
+                .col(ColumnDef::new(Tokens::Hash).string().not_null().unique_key())
+                .col(ColumnDef::new(Tokens::Scopes).string().not_null())
+                .col(ColumnDef::new(Tokens::Created).timestamp().not_null())
+                .col(ColumnDef::new(Tokens::LastUsed).timestamp())
+                .foreign_key(ForeignKey::create()
+                    .name("FK_tokens_user_id")
+                    .from(Tokens::Table, Tokens::UserId)
+                    .to(Users::Table, Users::Id)
+                    .on_delete(ForeignKeyAction::Cascade))
+                .build_any(builder),
+        ]
+    }),
+    ("V3_Roles", |builder| {
+        // The `admin` column is deprecated and no longer read or written, it
+        // is kept so that the database still works after a downgrade to a
+        // version without roles (new users are not admins there).
+        vec![
+            Table::alter()
+                .table(Users::Table)
+                .add_column(ColumnDef::new(Users::Role).string().not_null().default("User"))
+                .build_any(builder),
+            "update users set role = 'Admin' where admin".to_owned(),
+        ]
+    }),
+    ("V4_AuditLog", |builder| {
+        vec![
+            Table::create()
+                .table(AuditLog::Table)
+                .col(ColumnDef::new(AuditLog::Id).integer().auto_increment().primary_key())
+                .col(ColumnDef::new(AuditLog::UserId).integer())
+                .col(ColumnDef::new(AuditLog::Username).string().not_null())
+                .col(ColumnDef::new(AuditLog::Action).string().not_null())
+                .col(ColumnDef::new(AuditLog::Ip).string().not_null())
+                .col(ColumnDef::new(AuditLog::Details).text().not_null())
+                .col(ColumnDef::new(AuditLog::Created).timestamp().not_null())
+                .foreign_key(ForeignKey::create()
+                    .name("FK_audit_log_user_id")
+                    .from(AuditLog::Table, AuditLog::UserId)
+                    .to(Users::Table, Users::Id)
+                    .on_delete(ForeignKeyAction::SetNull))
+                .build_any(builder),
+        ]
+    }),
+    ("V5_CommentSearch", |_| {
+        if cfg!(feature = "postgres") {
+            vec![
+                "alter table comments add column search tsvector generated always as (to_tsvector('simple', \
+                    name || ' ' || email || ' ' || website || ' ' || ip || ' ' || markdown)) stored".to_owned(),
+                "create index comments_search_index on comments using gin (search)".to_owned(),
+            ]
+        } else {
+            vec![
+                "create virtual table comments_fts using fts5(name, email, website, ip, markdown, \
+                    content='comments', content_rowid='id')".to_owned(),
+                "create trigger comments_fts_insert after insert on comments begin \
+                    insert into comments_fts(rowid, name, email, website, ip, markdown) \
+                    values (new.id, new.name, new.email, new.website, new.ip, new.markdown); \
+                    end".to_owned(),
+                "create trigger comments_fts_delete after delete on comments begin \
+                    insert into comments_fts(comments_fts, rowid, name, email, website, ip, markdown) \
+                    values ('delete', old.id, old.name, old.email, old.website, old.ip, old.markdown); \
+                    end".to_owned(),
+                "create trigger comments_fts_update after update on comments begin \
+                    insert into comments_fts(comments_fts, rowid, name, email, website, ip, markdown) \
+                    values ('delete', old.id, old.name, old.email, old.website, old.ip, old.markdown); \
+                    insert into comments_fts(rowid, name, email, website, ip, markdown) \
+                    values (new.id, new.name, new.email, new.website, new.ip, new.markdown); \
+                    end".to_owned(),
+                "insert into comments_fts(comments_fts) values ('rebuild')".to_owned(),
+            ]
+        }
+    }),
+    ("V6_SoftDelete", |builder| {
+        vec![
+            Table::alter()
+                .table(Comments::Table)
+                .add_column(ColumnDef::new(Comments::DeletedAt).timestamp())
+                .build_any(builder),
+            Table::alter()
+                .table(Threads::Table)
+                .add_column(ColumnDef::new(Threads::DeletedAt).timestamp())
+                .build_any(builder),
+        ]
+    }),
+    ("V7_ThreadAliases", |builder| {
+        vec![
+            Table::create()
+                .table(ThreadAliases::Table)
+                .col(ColumnDef::new(ThreadAliases::Id).integer().auto_increment().primary_key())
+                .col(ColumnDef::new(ThreadAliases::ThreadId).integer().not_null())
+                .col(ColumnDef::new(ThreadAliases::Name).string().not_null().unique_key())
+                .foreign_key(ForeignKey::create()
+                    .name("FK_thread_aliases_thread_id")
+                    .from(ThreadAliases::Table, ThreadAliases::ThreadId)
+                    .to(Threads::Table, Threads::Id)
+                    .on_delete(ForeignKeyAction::Cascade))
+                .build_any(builder),
+        ]
+    }),
+    ("V8_ThreadSettings", |builder| {
+        vec![
+            Table::alter()
+                .table(Threads::Table)
+                .add_column(ColumnDef::new(Threads::Locked).boolean().not_null().default(false))
+                .build_any(builder),
+            Table::alter()
+                .table(Threads::Table)
+                .add_column(ColumnDef::new(Threads::ClosesAt).timestamp())
+                .build_any(builder),
+            Table::alter()
+                .table(Threads::Table)
+                .add_column(ColumnDef::new(Threads::ModerateAll).boolean())
+                .build_any(builder),
+            Table::alter()
+                .table(Threads::Table)
+                .add_column(ColumnDef::new(Threads::MaxDepth).integer())
+                .build_any(builder),
+            Table::alter()
+                .table(Threads::Table)
+                .add_column(ColumnDef::new(Threads::RequireName).boolean())
+                .build_any(builder),
+            Table::alter()
+                .table(Threads::Table)
+                .add_column(ColumnDef::new(Threads::RequireEmail).boolean())
+                .build_any(builder),
+        ]
+    }),
+    ("V9_ThreadCreated", |builder| {
+        vec![
+            Table::alter()
+                .table(Threads::Table)
+                .add_column(ColumnDef::new(Threads::Created).timestamp())
+                .build_any(builder),
+            Table::alter()
+                .table(Threads::Table)
+                .add_column(ColumnDef::new(Threads::CloseAfter).integer())
+                .build_any(builder),
+            "update threads set created = coalesce((select min(c.created) from comments c where c.thread_id = threads.id), \
+                current_timestamp)".to_owned(),
+        ]
+    }),
+    ("V10_ThreadUrl", |builder| {
+        vec![
+            Table::alter()
+                .table(Threads::Table)
+                .add_column(ColumnDef::new(Threads::Url).string())
+                .build_any(builder),
+        ]
+    }),
+    ("V11_Mentions", |builder| {
+        vec![
+            Table::create()
+                .table(Mentions::Table)
+                .col(ColumnDef::new(Mentions::Id).integer().auto_increment().primary_key())
+                .col(ColumnDef::new(Mentions::CommentId).integer().not_null())
+                .col(ColumnDef::new(Mentions::TargetId).integer().not_null())
+                .col(ColumnDef::new(Mentions::Kind).string().not_null())
+                .foreign_key(ForeignKey::create()
+                    .name("FK_mentions_comment_id")
+                    .from(Mentions::Table, Mentions::CommentId)
+                    .to(Comments::Table, Comments::Id)
+                    .on_delete(ForeignKeyAction::Cascade))
+                .foreign_key(ForeignKey::create()
+                    .name("FK_mentions_target_id")
+                    .from(Mentions::Table, Mentions::TargetId)
+                    .to(Comments::Table, Comments::Id)
+                    .on_delete(ForeignKeyAction::Cascade))
+                .build_any(builder),
+            Index::create()
+                .name("IDX_mentions_comment_id")
+                .table(Mentions::Table)
+                .col(Mentions::CommentId)
+                .build_any(builder),
+            Index::create()
+                .name("IDX_mentions_target_id")
+                .table(Mentions::Table)
+                .col(Mentions::TargetId)
+                .build_any(builder),
+        ]
+    }),
+    ("V12_Attachments", |builder| {
+        vec![
+            Table::create()
+                .table(Attachments::Table)
+                .col(ColumnDef::new(Attachments::Id).integer().auto_increment().primary_key())
+                .col(ColumnDef::new(Attachments::CommentId).integer())
+                .col(ColumnDef::new(Attachments::Key).string().not_null().unique_key())
+                .col(ColumnDef::new(Attachments::ThumbnailKey).string().not_null())
+                .col(ColumnDef::new(Attachments::ContentType).string().not_null())
+                .col(ColumnDef::new(Attachments::Size).big_integer().not_null())
+                .col(ColumnDef::new(Attachments::Width).integer().not_null())
+                .col(ColumnDef::new(Attachments::Height).integer().not_null())
+                .col(ColumnDef::new(Attachments::Ip).string().not_null())
+                .col(ColumnDef::new(Attachments::Created).timestamp().not_null())
+                .foreign_key(ForeignKey::create()
+                    .name("FK_attachments_comment_id")
+                    .from(Attachments::Table, Attachments::CommentId)
+                    .to(Comments::Table, Comments::Id)
+                    .on_delete(ForeignKeyAction::SetNull))
+                .build_any(builder),
+        ]
+    }),
+    ("V13_Modified", |builder| {
+        vec![
+            Table::alter()
+                .table(Comments::Table)
+                .add_column(ColumnDef::new(Comments::Modified).timestamp())
+                .build_any(builder),
+            Table::alter()
+                .table(Threads::Table)
+                .add_column(ColumnDef::new(Threads::Modified).timestamp())
+                .build_any(builder),
+            "update comments set modified = coalesce(deleted_at, created)".to_owned(),
+            "update threads set modified = coalesce(deleted_at, created)".to_owned(),
+        ]
+    }),
+    ("V14_ImportedComments", |builder| {
+        vec![
+            Table::alter()
+                .table(Comments::Table)
+                .add_column(ColumnDef::new(Comments::Imported).boolean().not_null().default(false))
+                .build_any(builder),
+            "update comments set imported = true where ip = '' and website like 'https://disqus.com/by/%'".to_owned(),
+        ]
+    }),
 ];
@@ -25,17 +25,37 @@ use sea_query::PostgresQueryBuilder;
 #[cfg(feature = "postgres")]
 sea_query::sea_query_driver_postgres!();
 
+pub mod attachments;
+pub mod audit;
 pub mod comments;
+pub mod mentions;
 pub mod threads;
 pub mod users;
 pub mod sessions;
+pub mod tokens;
 pub mod migrations;
 
+/// Deserializes a nullable field that may also be absent, so that an absent
+/// field becomes `None` and `null` becomes `Some(None)`. Must be used together
+/// with `#[serde(default)]`.
+pub fn deserialize_nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: serde::Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
 #[derive(serde::Serialize)]
 pub struct Page<T> {
     pub content: Vec<T>,
-    pub remaining: usize,
+    /// The number of items after this page. Not counted for cursor-based
+    /// pages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<usize>,
     pub limit: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Clone)]
@@ -46,6 +66,19 @@ pub enum Pool {
     Pool(sqlx::PgPool),
 }
 
+#[cfg(not(feature = "postgres"))]
+pub type DbRow = sqlx::sqlite::SqliteRow;
+
+#[cfg(feature = "postgres")]
+pub type DbRow = sqlx::postgres::PgRow;
+
+pub enum Transaction {
+    #[cfg(not(feature = "postgres"))]
+    Transaction(sqlx::Transaction<'static, sqlx::Sqlite>),
+    #[cfg(feature = "postgres")]
+    Transaction(sqlx::Transaction<'static, sqlx::Postgres>),
+}
+
 #[derive(Error, Debug)]
 pub enum DbError {
     #[error("SQL error")]
@@ -186,6 +219,79 @@ impl Pool {
     }
 }
 
+impl Pool {
+    pub async fn begin(&self) -> Result<Transaction, DbError> {
+        let Pool::Pool(pool) = self;
+        Ok(Transaction::Transaction(pool.begin().await?))
+    }
+}
+
+impl Transaction {
+    pub async fn commit(self) -> Result<(), DbError> {
+        let Transaction::Transaction(tx) = self;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    pub async fn select(&mut self, query: &SelectStatement) -> Result<Vec<DbRow>, DbError> {
+        let Transaction::Transaction(tx) = self;
+        let (sql, values) = query.build(SqliteQueryBuilder);
+        Ok(sea_query_driver_sqlite::bind_query(sqlx::query(&sql), &values).fetch_all(&mut *tx).await?)
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    pub async fn insert(&mut self, query: &InsertStatement) -> Result<(), DbError> {
+        let Transaction::Transaction(tx) = self;
+        let (sql, values) = query.build(SqliteQueryBuilder);
+        sea_query_driver_sqlite::bind_query(sqlx::query(&sql), &values).execute(&mut *tx).await?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    pub async fn update(&mut self, query: &UpdateStatement) -> Result<u64, DbError> {
+        let Transaction::Transaction(tx) = self;
+        let (sql, values) = query.build(SqliteQueryBuilder);
+        Ok(sea_query_driver_sqlite::bind_query(sqlx::query(&sql), &values).execute(&mut *tx).await?.rows_affected())
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    pub async fn delete(&mut self, query: &DeleteStatement) -> Result<u64, DbError> {
+        let Transaction::Transaction(tx) = self;
+        let (sql, values) = query.build(SqliteQueryBuilder);
+        Ok(sea_query_driver_sqlite::bind_query(sqlx::query(&sql), &values).execute(&mut *tx).await?.rows_affected())
+    }
+
+    #[cfg(feature = "postgres")]
+    pub async fn select(&mut self, query: &SelectStatement) -> Result<Vec<DbRow>, DbError> {
+        let Transaction::Transaction(tx) = self;
+        let (sql, values) = query.build(PostgresQueryBuilder);
+        Ok(sea_query_driver_postgres::bind_query(sqlx::query(&sql), &values).fetch_all(&mut *tx).await?)
+    }
+
+    #[cfg(feature = "postgres")]
+    pub async fn insert(&mut self, query: &InsertStatement) -> Result<(), DbError> {
+        let Transaction::Transaction(tx) = self;
+        let (sql, values) = query.build(PostgresQueryBuilder);
+        sea_query_driver_postgres::bind_query(sqlx::query(&sql), &values).execute(&mut *tx).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "postgres")]
+    pub async fn update(&mut self, query: &UpdateStatement) -> Result<u64, DbError> {
+        let Transaction::Transaction(tx) = self;
+        let (sql, values) = query.build(PostgresQueryBuilder);
+        Ok(sea_query_driver_postgres::bind_query(sqlx::query(&sql), &values).execute(&mut *tx).await?.rows_affected())
+    }
+
+    #[cfg(feature = "postgres")]
+    pub async fn delete(&mut self, query: &DeleteStatement) -> Result<u64, DbError> {
+        let Transaction::Transaction(tx) = self;
+        let (sql, values) = query.build(PostgresQueryBuilder);
+        Ok(sea_query_driver_postgres::bind_query(sqlx::query(&sql), &values).execute(&mut *tx).await?.rows_affected())
+    }
+}
+
 pub async fn count_remaining(
     pool: &Pool,
     length: usize,
@@ -11,7 +11,7 @@ use sqlx::Row;
 
 use crate::db::{DbError, Pool, users::Users};
 
-use super::users::User;
+use super::users::{User, convert_role};
 
 #[derive(Iden)]
 pub enum Sessions {
@@ -40,7 +40,7 @@ pub async fn get_session(pool: &Pool, session_id: &str) -> Result<Option<Session
             (Users::Table, Users::Email),
             (Users::Table, Users::Website),
             (Users::Table, Users::Trusted),
-            (Users::Table, Users::Admin),
+            (Users::Table, Users::Role),
         ])
         .from(Sessions::Table)
         .inner_join(Users::Table, Expr::tbl(Users::Table, Users::Id).equals(Sessions::Table, Sessions::UserId))
@@ -58,7 +58,7 @@ pub async fn get_session(pool: &Pool, session_id: &str) -> Result<Option<Session
                 email: row.try_get(5)?,
                 website: row.try_get(6)?,
                 trusted: row.try_get(7)?,
-                admin: row.try_get(8)?,
+                role: convert_role(row.try_get(8)?)?,
             },
         }))
     } else {
@@ -5,12 +5,15 @@
 
 //! DB queries related to threads
 
-use sea_query::{Expr, Func, Iden, Query, SelectStatement, SimpleExpr};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use sea_query::{Expr, Func, Iden, Query, SelectStatement, SimpleExpr, Value};
 use sqlx::Row;
 
-use crate::db::{DbError, Pool, comments::Comments};
+use std::collections::HashMap;
 
-use super::{Page, count_remaining};
+use crate::db::{DbError, Pool, Transaction, comments::{CommentStatus, Comments}};
+
+use super::{Page, count_remaining, deserialize_nullable};
 
 #[derive(Iden)]
 pub enum Threads {
@@ -18,6 +21,25 @@ pub enum Threads {
     Id,
     Name,
     Title,
+    DeletedAt,
+    Locked,
+    ClosesAt,
+    ModerateAll,
+    MaxDepth,
+    RequireName,
+    RequireEmail,
+    Created,
+    CloseAfter,
+    Url,
+    Modified,
+}
+
+#[derive(Iden)]
+pub enum ThreadAliases {
+    Table,
+    Id,
+    ThreadId,
+    Name,
 }
 
 #[derive(serde::Serialize)]
@@ -25,18 +47,112 @@ pub struct Thread {
     pub id: i32,
     pub name: String,
     pub title: Option<String>,
+    pub url: Option<String>,
+    pub comments: i64,
+    pub deleted_at: Option<String>,
+    pub locked: bool,
+    pub closes_at: Option<DateTime<Utc>>,
+    pub moderate_all: Option<bool>,
+    pub max_depth: Option<i32>,
+    pub require_name: Option<bool>,
+    pub require_email: Option<bool>,
+    pub close_after: Option<i32>,
+    pub created: DateTime<Utc>,
+}
+
+impl Thread {
+    /// Whether new comments can be posted in the thread.
+    pub fn is_open(&self) -> bool {
+        !self.locked && self.deleted_at.is_none() && self.closes_at.map(|d| d > Utc::now()).unwrap_or(true)
+    }
+}
+
+/// A thread as shown in the public thread listing.
+#[derive(serde::Serialize)]
+pub struct PublicThread {
+    pub name: String,
+    pub title: Option<String>,
+    pub url: Option<String>,
     pub comments: i64,
+    pub last_comment: String,
+    pub last_comment_timestamp: i64,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadSort {
+    Active,
+    MostCommented,
 }
 
 #[derive(serde::Deserialize)]
 pub struct NewThread {
     pub name: String,
     pub title: Option<String>,
+    pub url: Option<String>,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Serialize)]
+pub struct ThreadAlias {
+    pub id: i32,
+    pub thread_id: i32,
+    pub name: String,
+}
+
+/// Fields that are absent are left unchanged. Thread settings set to `null`
+/// fall back to the global settings.
+#[derive(serde::Deserialize, Default)]
+#[serde(default)]
 pub struct UpdateThread {
-    pub title: Option<String>,
+    pub name: Option<String>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    pub title: Option<Option<String>>,
+    pub locked: Option<bool>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    pub closes_at: Option<Option<DateTime<Utc>>>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    pub moderate_all: Option<Option<bool>>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    pub max_depth: Option<Option<i32>>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    pub require_name: Option<Option<bool>>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    pub require_email: Option<Option<bool>>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    pub close_after: Option<Option<i32>>,
+}
+
+impl UpdateThread {
+    /// Applies the changes to a thread.
+    pub fn apply(&self, thread: &mut Thread) {
+        if let Some(name) = &self.name {
+            thread.name = name.clone();
+        }
+        if let Some(title) = &self.title {
+            thread.title = title.clone();
+        }
+        if let Some(locked) = self.locked {
+            thread.locked = locked;
+        }
+        if let Some(closes_at) = self.closes_at {
+            thread.closes_at = closes_at;
+        }
+        if let Some(moderate_all) = self.moderate_all {
+            thread.moderate_all = moderate_all;
+        }
+        if let Some(max_depth) = self.max_depth {
+            thread.max_depth = max_depth;
+        }
+        if let Some(require_name) = self.require_name {
+            thread.require_name = require_name;
+        }
+        if let Some(require_email) = self.require_email {
+            thread.require_email = require_email;
+        }
+        if let Some(close_after) = self.close_after {
+            thread.close_after = close_after;
+        }
+    }
 }
 
 fn get_default_thread_query() -> SelectStatement {
@@ -48,7 +164,20 @@ fn get_default_thread_query() -> SelectStatement {
                     .from(Comments::Table)
                     .and_where(Expr::tbl(Threads::Table, Threads::Id)
                         .equals(Comments::Table, Comments::ThreadId))
+                    .and_where(Expr::tbl(Comments::Table, Comments::DeletedAt).is_null())
                     .to_owned())))
+        .columns(vec![
+            Threads::DeletedAt,
+            Threads::Locked,
+            Threads::ClosesAt,
+            Threads::ModerateAll,
+            Threads::MaxDepth,
+            Threads::RequireName,
+            Threads::RequireEmail,
+            Threads::CloseAfter,
+            Threads::Created,
+            Threads::Url,
+        ])
         .to_owned()
 }
 
@@ -64,6 +193,16 @@ async fn query_threads(
             name: row.try_get(1)?,
             title: row.try_get(2)?,
             comments: row.try_get(3)?,
+            deleted_at: row.try_get::<Option<NaiveDateTime>, _>(4)?.map(|d| Utc.from_utc_datetime(&d).to_rfc3339()),
+            locked: row.try_get(5)?,
+            closes_at: row.try_get::<Option<NaiveDateTime>, _>(6)?.map(|d| Utc.from_utc_datetime(&d)),
+            moderate_all: row.try_get(7)?,
+            max_depth: row.try_get(8)?,
+            require_name: row.try_get(9)?,
+            require_email: row.try_get(10)?,
+            close_after: row.try_get(11)?,
+            created: Utc.from_utc_datetime(&row.try_get::<NaiveDateTime, _>(12)?),
+            url: row.try_get(13)?,
         });
     }
     Ok(content)
@@ -75,6 +214,20 @@ pub async fn get_thread_by_name(pool: &Pool, thread_name: &str) -> Result<Option
         .await?.into_iter().next())
 }
 
+/// Finds a thread by its name or by one of its aliases.
+pub async fn get_thread_by_name_or_alias(pool: &Pool, name: &str) -> Result<Option<Thread>, DbError> {
+    if let Some(thread) = get_thread_by_name(pool, name).await? {
+        return Ok(Some(thread));
+    }
+    Ok(query_threads(pool, get_default_thread_query()
+            .and_where(Expr::col(Threads::Id).in_subquery(Query::select()
+                    .column(ThreadAliases::ThreadId)
+                    .from(ThreadAliases::Table)
+                    .and_where(Expr::col(ThreadAliases::Name).eq(name))
+                    .to_owned())))
+        .await?.into_iter().next())
+}
+
 pub async fn get_thread_by_id(pool: &Pool, id: i32) -> Result<Option<Thread>, DbError> {
     Ok(query_threads(&pool, get_default_thread_query()
             .and_where(Expr::col(Threads::Id).eq(id)))
@@ -82,40 +235,265 @@ pub async fn get_thread_by_id(pool: &Pool, id: i32) -> Result<Option<Thread>, Db
 }
 
 pub async fn create_thread(pool: &Pool, data: NewThread) -> Result<Thread, DbError> {
+    let created = Utc::now();
     let id = pool.insert_returning(Query::insert()
         .into_table(Threads::Table)
-        .columns(vec![Threads::Name, Threads::Title])
-        .values_panic(vec![data.name.as_str().into(), data.title.clone().into()])
+        .columns(vec![Threads::Name, Threads::Title, Threads::Url, Threads::Created, Threads::Modified])
+        .values_panic(vec![
+            data.name.as_str().into(),
+            data.title.clone().into(),
+            data.url.clone().into(),
+            created.naive_utc().into(),
+            created.naive_utc().into(),
+        ])
         .returning_col(Threads::Id)).await?;
     Ok(Thread {
         id: id as i32,
         name: data.name,
         title: data.title,
+        url: data.url,
         comments: 0,
+        deleted_at: None,
+        locked: false,
+        closes_at: None,
+        moderate_all: None,
+        max_depth: None,
+        require_name: None,
+        require_email: None,
+        close_after: None,
+        created,
     })
 }
 
-pub async fn get_threads(pool: &Pool, limit: usize, offset: usize) -> Result<Page<Thread>, DbError> {
+/// Gets a page of threads, if `deleted` is true only threads in the trash are
+/// returned.
+pub async fn get_threads(pool: &Pool, deleted: bool, limit: usize, offset: usize) -> Result<Page<Thread>, DbError> {
+    let deleted_condition = if deleted {
+        Expr::col(Threads::DeletedAt).is_not_null()
+    } else {
+        Expr::col(Threads::DeletedAt).is_null()
+    };
     let mut query = get_default_thread_query();
-    query.order_by(Threads::Name, sea_query::Order::Asc)
+    query.and_where(deleted_condition.clone())
+        .order_by(Threads::Name, sea_query::Order::Asc)
         .limit(limit as u64)
         .offset(offset as u64);
     let content = query_threads(pool, &query).await?;
     let remaining = count_remaining(pool, content.len(), limit, offset,
         Query::select().from(Threads::Table)
-            .expr(Expr::count(Expr::col(Threads::Id)))).await?;
-    Ok(Page { content, remaining, limit })
+            .expr(Expr::count(Expr::col(Threads::Id)))
+            .and_where(deleted_condition)).await?;
+    Ok(Page { content, remaining: Some(remaining), limit, next_cursor: None })
+}
+
+/// Gets threads that have approved comments, either ordered by the time of
+/// the latest comment or by the number of comments.
+pub async fn get_public_threads(pool: &Pool, sort: ThreadSort, limit: usize) -> Result<Vec<PublicThread>, DbError> {
+    let order = match sort {
+        ThreadSort::Active => "max(comments.created)",
+        ThreadSort::MostCommented => "count(comments.id)",
+    };
+    let mut rows = pool.select(Query::select()
+        .columns(vec![
+            (Threads::Table, Threads::Name),
+            (Threads::Table, Threads::Title),
+            (Threads::Table, Threads::Url),
+        ])
+        .expr(Expr::tbl(Comments::Table, Comments::Id).count())
+        .expr(Expr::tbl(Comments::Table, Comments::Created).max())
+        .from(Threads::Table)
+        .inner_join(Comments::Table, Expr::tbl(Comments::Table, Comments::ThreadId).equals(Threads::Table, Threads::Id))
+        .and_where(Expr::tbl(Comments::Table, Comments::Status).eq(CommentStatus::Approved))
+        .and_where(Expr::tbl(Comments::Table, Comments::DeletedAt).is_null())
+        .and_where(Expr::tbl(Threads::Table, Threads::DeletedAt).is_null())
+        .group_by_columns(vec![
+            (Threads::Table, Threads::Id),
+            (Threads::Table, Threads::Name),
+            (Threads::Table, Threads::Title),
+            (Threads::Table, Threads::Url),
+        ])
+        .order_by_customs(vec![(order.to_owned(), sea_query::Order::Desc)])
+        .order_by((Threads::Table, Threads::Id), sea_query::Order::Desc)
+        .limit(limit as u64)).await?.into_iter();
+    let mut content = Vec::new();
+    while let Some(row) = rows.next() {
+        let last_comment = Utc.from_utc_datetime(&row.try_get::<NaiveDateTime, _>(4)?);
+        content.push(PublicThread {
+            name: row.try_get(0)?,
+            title: row.try_get(1)?,
+            url: row.try_get(2)?,
+            comments: row.try_get(3)?,
+            last_comment: last_comment.to_rfc3339(),
+            last_comment_timestamp: last_comment.timestamp(),
+        });
+    }
+    Ok(content)
+}
+
+/// Gets all threads that are not in the trash. If `since` is given only
+/// threads that were created, or had comments posted or deleted, after that
+/// time are returned.
+pub async fn get_changed_threads(pool: &Pool, since: Option<DateTime<Utc>>) -> Result<Vec<Thread>, DbError> {
+    let mut query = get_default_thread_query();
+    query.and_where(Expr::col(Threads::DeletedAt).is_null())
+        .order_by(Threads::Name, sea_query::Order::Asc);
+    if let Some(since) = since {
+        query.and_where(Expr::col(Threads::Modified).gte(since.naive_utc())
+            .or(Expr::col(Threads::Id).in_subquery(Query::select()
+                    .column(Comments::ThreadId)
+                    .from(Comments::Table)
+                    .and_where(Expr::col(Comments::Modified).gte(since.naive_utc()))
+                    .to_owned())));
+    }
+    query_threads(pool, &query).await
+}
+
+/// Returns the names of threads that have been moved to the trash since the
+/// given time as well as the aliases (i.e. old names of renamed or merged
+/// threads) of threads that have changed since then.
+pub async fn get_removed_thread_names(pool: &Pool, since: Option<DateTime<Utc>>) -> Result<Vec<String>, DbError> {
+    let mut trashed = Query::select();
+    trashed.column(Threads::Name)
+        .from(Threads::Table)
+        .and_where(Expr::col(Threads::DeletedAt).is_not_null());
+    let mut aliases = Query::select();
+    aliases.column(ThreadAliases::Name)
+        .from(ThreadAliases::Table);
+    if let Some(since) = since {
+        trashed.and_where(Expr::col(Threads::Modified).gte(since.naive_utc()));
+        aliases.and_where(Expr::col(ThreadAliases::ThreadId).in_subquery(Query::select()
+                .column(Threads::Id)
+                .from(Threads::Table)
+                .and_where(Expr::col(Threads::Modified).gte(since.naive_utc())
+                    .or(Expr::col(Threads::Id).in_subquery(Query::select()
+                            .column(Comments::ThreadId)
+                            .from(Comments::Table)
+                            .and_where(Expr::col(Comments::Modified).gte(since.naive_utc()))
+                            .to_owned())))
+                .to_owned()));
+    }
+    let mut names = Vec::new();
+    for query in &[trashed, aliases] {
+        for row in pool.select(query).await? {
+            names.push(row.try_get(0)?);
+        }
+    }
+    Ok(names)
 }
 
+/// Updates the fields of a thread that are present in `data`.
 pub async fn update_thread(pool: &Pool, id: i32, data: UpdateThread) -> Result<(), DbError> {
+    let mut values: Vec<(Threads, Value)> = Vec::new();
+    if let Some(name) = data.name {
+        values.push((Threads::Name, name.into()));
+    }
+    if let Some(title) = data.title {
+        values.push((Threads::Title, title.into()));
+    }
+    if let Some(locked) = data.locked {
+        values.push((Threads::Locked, locked.into()));
+    }
+    if let Some(closes_at) = data.closes_at {
+        values.push((Threads::ClosesAt, closes_at.map(|d| d.naive_utc()).into()));
+    }
+    if let Some(moderate_all) = data.moderate_all {
+        values.push((Threads::ModerateAll, moderate_all.into()));
+    }
+    if let Some(max_depth) = data.max_depth {
+        values.push((Threads::MaxDepth, max_depth.into()));
+    }
+    if let Some(require_name) = data.require_name {
+        values.push((Threads::RequireName, require_name.into()));
+    }
+    if let Some(require_email) = data.require_email {
+        values.push((Threads::RequireEmail, require_email.into()));
+    }
+    if let Some(close_after) = data.close_after {
+        values.push((Threads::CloseAfter, close_after.into()));
+    }
+    if values.is_empty() {
+        return Ok(());
+    }
+    values.push((Threads::Modified, Utc::now().naive_utc().into()));
+    let mut update = Query::update();
+    update.table(Threads::Table);
+    for (column, value) in values {
+        update.value(column, value);
+    }
+    pool.update(update
+        .and_where(Expr::col(Threads::Id).eq(id))).await?;
+    Ok(())
+}
+
+pub async fn update_thread_metadata(pool: &Pool, id: i32, title: Option<String>, url: Option<String>) -> Result<(), DbError> {
     pool.update(Query::update()
         .table(Threads::Table)
-        .value(Threads::Title, data.title.into())
+        .value(Threads::Title, title.into())
+        .value(Threads::Url, url.into())
+        .value(Threads::Modified, Utc::now().naive_utc().into())
         .and_where(Expr::col(Threads::Id).eq(id))).await?;
     Ok(())
 }
 
+/// Moves all comments from one thread to another and deletes the source
+/// thread. The name and aliases of the source thread become aliases of the
+/// target thread. Returns the number of moved comments.
+pub async fn merge_threads(tx: &mut Transaction, source_id: i32, target_id: i32) -> Result<u64, DbError> {
+    let now = Utc::now().naive_utc();
+    let moved = tx.update(Query::update()
+        .table(Comments::Table)
+        .value(Comments::ThreadId, target_id.into())
+        .value(Comments::Modified, now.into())
+        .and_where(Expr::col(Comments::ThreadId).eq(source_id))).await?;
+    tx.update(Query::update()
+        .table(ThreadAliases::Table)
+        .value(ThreadAliases::ThreadId, target_id.into())
+        .and_where(Expr::col(ThreadAliases::ThreadId).eq(source_id))).await?;
+    tx.update(Query::update()
+        .table(Threads::Table)
+        .value(Threads::Modified, now.into())
+        .and_where(Expr::col(Threads::Id).eq(target_id))).await?;
+    for row in tx.select(Query::select()
+        .column(Threads::Name)
+        .from(Threads::Table)
+        .and_where(Expr::col(Threads::Id).eq(source_id))).await? {
+        let name: String = row.try_get(0)?;
+        tx.insert(Query::insert()
+            .into_table(ThreadAliases::Table)
+            .columns(vec![ThreadAliases::ThreadId, ThreadAliases::Name])
+            .values_panic(vec![target_id.into(), name.into()])).await?;
+    }
+    tx.delete(Query::delete()
+        .from_table(Threads::Table)
+        .and_where(Expr::col(Threads::Id).eq(source_id))).await?;
+    Ok(moved)
+}
+
+/// Moves a thread to the trash. The comments are kept but are hidden along
+/// with the thread.
 pub async fn delete_thread(pool: &Pool, id: i32) -> Result<(), DbError> {
+    pool.update(Query::update()
+        .table(Threads::Table)
+        .value(Threads::DeletedAt, Utc::now().naive_utc().into())
+        .value(Threads::Modified, Utc::now().naive_utc().into())
+        .and_where(Expr::col(Threads::Id).eq(id))).await?;
+    Ok(())
+}
+
+pub async fn restore_thread(pool: &Pool, id: i32) -> Result<(), DbError> {
+    pool.update(Query::update()
+        .table(Threads::Table)
+        .value(Threads::DeletedAt, Value::Null)
+        .value(Threads::Modified, Utc::now().naive_utc().into())
+        .and_where(Expr::col(Threads::Id).eq(id))).await?;
+    Ok(())
+}
+
+/// Permanently deletes a thread and all of its comments.
+pub async fn purge_thread(pool: &Pool, id: i32) -> Result<(), DbError> {
+    pool.delete(Query::delete()
+        .from_table(ThreadAliases::Table)
+        .and_where(Expr::col(ThreadAliases::ThreadId).eq(id))).await?;
     pool.delete(Query::delete()
         .from_table(Comments::Table)
         .and_where(Expr::col(Comments::ThreadId).eq(id))).await?;
@@ -125,3 +503,92 @@ pub async fn delete_thread(pool: &Pool, id: i32) -> Result<(), DbError> {
     Ok(())
 }
 
+/// Permanently deletes threads that were moved to the trash before the given
+/// time.
+pub async fn purge_deleted_threads(pool: &Pool, before: DateTime<Utc>) -> Result<u64, DbError> {
+    let mut rows = pool.select(Query::select()
+        .column(Threads::Id)
+        .from(Threads::Table)
+        .and_where(Expr::col(Threads::DeletedAt).lt(before.naive_utc()))).await?.into_iter();
+    let mut purged = 0;
+    while let Some(row) = rows.next() {
+        purge_thread(pool, row.try_get(0)?).await?;
+        purged += 1;
+    }
+    Ok(purged)
+}
+
+pub async fn get_thread_aliases(pool: &Pool, thread_id: i32) -> Result<Vec<ThreadAlias>, DbError> {
+    let mut rows = pool.select(Query::select()
+        .columns(vec![ThreadAliases::Id, ThreadAliases::ThreadId, ThreadAliases::Name])
+        .from(ThreadAliases::Table)
+        .and_where(Expr::col(ThreadAliases::ThreadId).eq(thread_id))
+        .order_by(ThreadAliases::Name, sea_query::Order::Asc)).await?.into_iter();
+    let mut content = Vec::new();
+    while let Some(row) = rows.next() {
+        content.push(ThreadAlias {
+            id: row.try_get(0)?,
+            thread_id: row.try_get(1)?,
+            name: row.try_get(2)?,
+        });
+    }
+    Ok(content)
+}
+
+/// Maps aliases to the names of the threads they point to.
+pub async fn resolve_thread_aliases(pool: &Pool, names: Vec<&str>) -> Result<HashMap<String, String>, DbError> {
+    let mut rows = pool.select(Query::select()
+        .column((ThreadAliases::Table, ThreadAliases::Name))
+        .column((Threads::Table, Threads::Name))
+        .from(ThreadAliases::Table)
+        .inner_join(Threads::Table, Expr::tbl(Threads::Table, Threads::Id).equals(ThreadAliases::Table, ThreadAliases::ThreadId))
+        .and_where(Expr::tbl(ThreadAliases::Table, ThreadAliases::Name).is_in(names))).await?.into_iter();
+    let mut result = HashMap::new();
+    while let Some(row) = rows.next() {
+        result.insert(row.try_get(0)?, row.try_get(1)?);
+    }
+    Ok(result)
+}
+
+pub async fn create_thread_alias(pool: &Pool, thread_id: i32, name: &str) -> Result<ThreadAlias, DbError> {
+    let id = pool.insert_returning(Query::insert()
+        .into_table(ThreadAliases::Table)
+        .columns(vec![ThreadAliases::ThreadId, ThreadAliases::Name])
+        .values_panic(vec![thread_id.into(), name.into()])
+        .returning_col(ThreadAliases::Id)).await?;
+    Ok(ThreadAlias {
+        id,
+        thread_id,
+        name: name.to_owned(),
+    })
+}
+
+pub async fn delete_thread_alias(pool: &Pool, thread_id: i32, id: i32) -> Result<(), DbError> {
+    pool.delete(Query::delete()
+        .from_table(ThreadAliases::Table)
+        .and_where(Expr::col(ThreadAliases::Id).eq(id))
+        .and_where(Expr::col(ThreadAliases::ThreadId).eq(thread_id))).await?;
+    Ok(())
+}
+
+pub async fn delete_thread_alias_by_name(pool: &Pool, name: &str) -> Result<(), DbError> {
+    pool.delete(Query::delete()
+        .from_table(ThreadAliases::Table)
+        .and_where(Expr::col(ThreadAliases::Name).eq(name))).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_thread_distinguishes_absent_and_null() {
+        let data: UpdateThread = serde_json::from_str(r#"{"title": "Title", "max_depth": null}"#).unwrap();
+        assert_eq!(data.title, Some(Some("Title".to_owned())));
+        assert_eq!(data.max_depth, Some(None));
+        assert_eq!(data.locked, None);
+        assert_eq!(data.moderate_all, None);
+        assert_eq!(data.closes_at, None);
+    }
+}
@@ -0,0 +1,223 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! DB queries related to API tokens
+
+use std::fmt;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use sea_query::{Expr, Iden, Query, SelectStatement};
+use sqlx::Row;
+
+use crate::db::{DbError, Pool, users::{User, Users, convert_role}};
+
+#[derive(Iden)]
+pub enum Tokens {
+    Table,
+    Id,
+    UserId,
+    Name,
+    Hash,
+    Scopes,
+    Created,
+    LastUsed,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    #[serde(rename = "comments:read")]
+    CommentsRead,
+    #[serde(rename = "comments:moderate")]
+    CommentsModerate,
+    #[serde(rename = "threads:read")]
+    ThreadsRead,
+    #[serde(rename = "threads:write")]
+    ThreadsWrite,
+    #[serde(rename = "import")]
+    Import,
+    #[serde(rename = "users")]
+    Users,
+    #[serde(rename = "audit:read")]
+    AuditRead,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scope::CommentsRead => write!(f, "comments:read"),
+            Scope::CommentsModerate => write!(f, "comments:moderate"),
+            Scope::ThreadsRead => write!(f, "threads:read"),
+            Scope::ThreadsWrite => write!(f, "threads:write"),
+            Scope::Import => write!(f, "import"),
+            Scope::Users => write!(f, "users"),
+            Scope::AuditRead => write!(f, "audit:read"),
+        }
+    }
+}
+
+fn convert_scope(value: &str) -> Result<Scope, DbError> {
+    match value {
+        "comments:read" => Ok(Scope::CommentsRead),
+        "comments:moderate" => Ok(Scope::CommentsModerate),
+        "threads:read" => Ok(Scope::ThreadsRead),
+        "threads:write" => Ok(Scope::ThreadsWrite),
+        "import" => Ok(Scope::Import),
+        "users" => Ok(Scope::Users),
+        "audit:read" => Ok(Scope::AuditRead),
+        _ => Err(DbError::ColumnTypeError),
+    }
+}
+
+fn convert_scopes(value: &str) -> Result<Vec<Scope>, DbError> {
+    value.split(",").filter(|s| !s.is_empty()).map(convert_scope).collect()
+}
+
+fn join_scopes(scopes: &[Scope]) -> String {
+    scopes.iter().map(|s| s.to_string()).collect::<Vec<String>>().join(",")
+}
+
+#[derive(serde::Serialize)]
+pub struct Token {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created: String,
+    pub last_used: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+pub struct TokenUser {
+    pub id: i32,
+    pub scopes: Vec<Scope>,
+    pub user: User,
+}
+
+fn get_default_token_query() -> SelectStatement {
+    Query::select().from(Tokens::Table)
+        .columns(vec![
+            Tokens::Id,
+            Tokens::Name,
+            Tokens::Scopes,
+            Tokens::Created,
+            Tokens::LastUsed,
+        ])
+        .to_owned()
+}
+
+async fn query_tokens(
+    pool: &Pool,
+    select: &SelectStatement,
+) -> Result<Vec<Token>, DbError> {
+    let mut rows = pool.select(select).await?.into_iter();
+    let mut content = Vec::new();
+    while let Some(row) = rows.next() {
+        let naive_created: NaiveDateTime = row.try_get(3)?;
+        let naive_last_used: Option<NaiveDateTime> = row.try_get(4)?;
+        let scopes: String = row.try_get(2)?;
+        content.push(Token {
+            id: row.try_get(0)?,
+            name: row.try_get(1)?,
+            scopes: convert_scopes(&scopes)?,
+            created: Utc.from_utc_datetime(&naive_created).to_rfc3339(),
+            last_used: naive_last_used.map(|d| Utc.from_utc_datetime(&d).to_rfc3339()),
+        });
+    }
+    Ok(content)
+}
+
+pub async fn get_tokens_by_user_id(pool: &Pool, user_id: i32) -> Result<Vec<Token>, DbError> {
+    let mut query = get_default_token_query();
+    query.and_where(Expr::col(Tokens::UserId).eq(user_id))
+        .order_by(Tokens::Id, sea_query::Order::Asc);
+    query_tokens(pool, &query).await
+}
+
+pub async fn get_token_user_by_hash(pool: &Pool, hash: &str) -> Result<Option<TokenUser>, DbError> {
+    let result = pool.select_optional(Query::select()
+        .columns(vec![
+            (Tokens::Table, Tokens::Id),
+            (Tokens::Table, Tokens::Scopes),
+        ])
+        .columns(vec![
+            (Users::Table, Users::Id),
+            (Users::Table, Users::Username),
+            (Users::Table, Users::Name),
+            (Users::Table, Users::Email),
+            (Users::Table, Users::Website),
+            (Users::Table, Users::Trusted),
+            (Users::Table, Users::Role),
+        ])
+        .from(Tokens::Table)
+        .inner_join(Users::Table, Expr::tbl(Users::Table, Users::Id).equals(Tokens::Table, Tokens::UserId))
+        .and_where(Expr::tbl(Tokens::Table, Tokens::Hash).eq(hash)))
+        .await?;
+    if let Some(row) = result {
+        let scopes: String = row.try_get(1)?;
+        Ok(Some(TokenUser {
+            id: row.try_get(0)?,
+            scopes: convert_scopes(&scopes)?,
+            user: User {
+                id: row.try_get(2)?,
+                username: row.try_get(3)?,
+                name: row.try_get(4)?,
+                email: row.try_get(5)?,
+                website: row.try_get(6)?,
+                trusted: row.try_get(7)?,
+                role: convert_role(row.try_get(8)?)?,
+            },
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+pub async fn create_token(pool: &Pool, user_id: i32, hash: &str, data: NewToken) -> Result<Token, DbError> {
+    let created: DateTime<Utc> = Utc::now();
+    let id = pool.insert_returning(Query::insert()
+        .into_table(Tokens::Table)
+        .columns(vec![
+            Tokens::UserId,
+            Tokens::Name,
+            Tokens::Hash,
+            Tokens::Scopes,
+            Tokens::Created,
+        ])
+        .values_panic(vec![
+            user_id.into(),
+            data.name.as_str().into(),
+            hash.into(),
+            join_scopes(&data.scopes).into(),
+            created.naive_utc().into(),
+        ])
+        .returning_col(Tokens::Id)).await?;
+    Ok(Token {
+        id,
+        name: data.name,
+        scopes: data.scopes,
+        created: created.to_rfc3339(),
+        last_used: None,
+    })
+}
+
+pub async fn touch_token(pool: &Pool, id: i32) -> Result<(), DbError> {
+    pool.update(Query::update().table(Tokens::Table)
+        .value(Tokens::LastUsed, Utc::now().naive_utc().into())
+        .and_where(Expr::col(Tokens::Id).eq(id)))
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_token(pool: &Pool, user_id: i32, id: i32) -> Result<(), DbError> {
+    pool.delete(Query::delete().from_table(Tokens::Table)
+        .and_where(Expr::col(Tokens::Id).eq(id))
+        .and_where(Expr::col(Tokens::UserId).eq(user_id)))
+        .await?;
+    Ok(())
+}
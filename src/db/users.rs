@@ -7,7 +7,9 @@
 
 use super::{DbError, Page, Pool, count_remaining};
 
-use sea_query::{Expr, Iden, Query, SelectStatement};
+use std::fmt;
+
+use sea_query::{Expr, Iden, Query, SelectStatement, Value};
 use sqlx::Row;
 
 #[derive(Iden)]
@@ -20,9 +22,41 @@ pub enum Users {
     Email,
     Website,
     Trusted,
+    /// Deprecated, replaced by `Role`
+    Admin,
+    Role,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    User,
+    Moderator,
+    ThreadManager,
     Admin,
 }
 
+pub fn convert_role(value: &str) -> Result<Role, DbError> {
+    match value {
+        "User" => Ok(Role::User),
+        "Moderator" => Ok(Role::Moderator),
+        "ThreadManager" => Ok(Role::ThreadManager),
+        "Admin" => Ok(Role::Admin),
+        _ => Err(DbError::ColumnTypeError),
+    }
+}
+
+impl Into<Value> for Role {
+    fn into(self) -> Value {
+        self.to_string().into()
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 #[derive(serde::Serialize)]
 pub struct User {
     pub id: i32,
@@ -31,7 +65,7 @@ pub struct User {
     pub email: String,
     pub website: String,
     pub trusted: bool,
-    pub admin: bool,
+    pub role: Role,
 }
 
 pub struct Password {
@@ -47,7 +81,7 @@ pub struct NewUser {
     pub email: String,
     pub website: String,
     pub trusted: bool,
-    pub admin: bool,
+    pub role: Role,
 }
 
 #[derive(serde::Deserialize)]
@@ -58,7 +92,7 @@ pub struct UpdateUser {
     pub email: String,
     pub website: String,
     pub trusted: bool,
-    pub admin: bool,
+    pub role: Role,
 }
 
 fn get_default_user_query() -> SelectStatement {
@@ -70,7 +104,7 @@ fn get_default_user_query() -> SelectStatement {
             Users::Email,
             Users::Website,
             Users::Trusted,
-            Users::Admin,
+            Users::Role,
         ])
         .to_owned()
 }
@@ -89,7 +123,7 @@ async fn query_users(
             email: row.try_get(3)?,
             website: row.try_get(4)?,
             trusted: row.try_get(5)?,
-            admin: row.try_get(6)?,
+            role: convert_role(row.try_get(6)?)?,
         });
     }
     Ok(content)
@@ -128,7 +162,7 @@ pub async fn get_password_by_user_id(pool: &Pool, user_id: i32) -> Result<Option
 pub async fn admin_exists(pool: &Pool) -> Result<bool, DbError> {
     Ok(pool.select_optional(Query::select().from(Users::Table)
             .expr(Expr::value(1))
-            .and_where(Expr::col(Users::Admin).eq(true)))
+            .and_where(Expr::col(Users::Role).eq(Role::Admin)))
         .await?
         .is_some())
 }
@@ -143,7 +177,7 @@ pub async fn create_user(pool: &Pool, new_user: NewUser) -> Result<User, DbError
             Users::Email,
             Users::Website,
             Users::Trusted,
-            Users::Admin,
+            Users::Role,
         ])
         .values_panic(vec![
             new_user.username.as_str().into(),
@@ -152,7 +186,7 @@ pub async fn create_user(pool: &Pool, new_user: NewUser) -> Result<User, DbError
             new_user.email.as_str().into(),
             new_user.website.as_str().into(),
             new_user.trusted.into(),
-            new_user.admin.into(),
+            new_user.role.into(),
         ])
         .returning_col(Users::Id)).await?;
     Ok(User {
@@ -162,7 +196,7 @@ pub async fn create_user(pool: &Pool, new_user: NewUser) -> Result<User, DbError
         email: new_user.email,
         website: new_user.website,
         trusted: new_user.trusted,
-        admin: new_user.admin,
+        role: new_user.role,
     })
 }
 
@@ -186,7 +220,7 @@ pub async fn get_users(pool: &Pool, limit: usize, offset: usize) -> Result<Page<
     let content = query_users(pool, &query).await?;
     let remaining = count_remaining(pool, content.len(), limit, offset,
         Query::select().from(Users::Table).expr(Expr::count(Expr::col(Users::Id)))).await?;
-    Ok(Page { content, remaining, limit })
+    Ok(Page { content, remaining: Some(remaining), limit, next_cursor: None })
 }
 
 pub async fn update_user(pool: &Pool, id: i32, data: UpdateUser) -> Result<(), DbError> {
@@ -196,7 +230,7 @@ pub async fn update_user(pool: &Pool, id: i32, data: UpdateUser) -> Result<(), D
         .value(Users::Email, data.email.into())
         .value(Users::Website, data.website.into())
         .value(Users::Trusted, data.trusted.into())
-        .value(Users::Admin, data.admin.into())
+        .value(Users::Role, data.role.into())
         .and_where(Expr::col(Users::Id).eq(id))
         .to_owned();
     if let Some(password) = data.password {
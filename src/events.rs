@@ -0,0 +1,236 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Live updates of threads and the moderation queue using server-sent events
+
+use std::sync::Mutex;
+
+use actix_web::{HttpResponse, error, get, web::{self, Bytes}};
+use futures::{StreamExt, channel::mpsc::{Sender, channel}};
+use log::{debug, warn};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{auth, comment_thread::get_max_depth, db::{DbError, Pool, comments::{self, CommentStatus, PrivateComment, PublicComment}, threads, tokens::Scope}, normalize::normalize_thread_name, settings::Settings};
+
+#[derive(Clone, PartialEq)]
+pub enum Topic {
+    /// Public changes to the thread with the given id
+    Thread(i32),
+    /// Public changes to a thread that didn't exist when the client
+    /// subscribed, replaced by `Thread` once the thread has been created
+    NewThread(String),
+    /// All new and changed comments, used by the dashboard
+    Moderation,
+}
+
+/// Number of messages that can be queued for a client before it's
+/// disconnected.
+const CLIENT_BUFFER_SIZE: usize = 32;
+
+/// Maximum number of event streams per IP address.
+const MAX_CLIENTS_PER_IP: usize = 10;
+
+struct Client {
+    topic: Topic,
+    ip: String,
+    sender: Sender<Bytes>,
+}
+
+/// Keeps track of connected event stream clients and sends events to them.
+pub struct Broadcaster {
+    clients: Mutex<Vec<Client>>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Broadcaster {
+        Broadcaster { clients: Mutex::new(Vec::new()) }
+    }
+
+    fn subscribe(&self, topic: Topic, ip: String) -> actix_web::Result<HttpResponse> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.iter().filter(|client| client.ip == ip).count() >= MAX_CLIENTS_PER_IP {
+            Err(error::ErrorTooManyRequests("TOO_MANY_CONNECTIONS"))?;
+        }
+        let (mut sender, receiver) = channel(CLIENT_BUFFER_SIZE);
+        // Tells the client to wait 10 seconds before reconnecting
+        sender.try_send(Bytes::from_static(b"retry: 10000\n\n")).ok();
+        clients.push(Client { topic, ip, sender });
+        Ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .streaming(receiver.map(Ok::<_, actix_web::Error>)))
+    }
+
+    /// Sends a message to the clients subscribed to the topic. Clients that
+    /// have disconnected or aren't keeping up are removed.
+    fn send(&self, topic: &Topic, message: Bytes) {
+        self.clients.lock().unwrap().retain_mut(|client| {
+            client.topic != *topic || client.sender.try_send(message.clone()).is_ok()
+        });
+    }
+
+    /// Gets the topic of a thread. Clients waiting for the thread to be created
+    /// are moved to the topic so that they keep receiving events if the thread
+    /// is renamed.
+    fn get_thread_topic(&self, thread_id: i32, thread_name: &str) -> Topic {
+        let topic = Topic::Thread(thread_id);
+        for client in self.clients.lock().unwrap().iter_mut() {
+            if matches!(&client.topic, Topic::NewThread(name) if name == thread_name) {
+                client.topic = topic.clone();
+            }
+        }
+        topic
+    }
+
+    fn has_subscribers(&self, topic: &Topic) -> bool {
+        self.clients.lock().unwrap().iter().any(|client| client.topic == *topic)
+    }
+
+    pub fn publish<T: Serialize>(&self, topic: &Topic, event: &str, data: &T) {
+        match serde_json::to_string(data) {
+            Ok(data) => self.send(topic, Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))),
+            Err(err) => warn!("Unable to serialize {} event: {}", event, err),
+        }
+    }
+
+    /// Sends a keep-alive message to every client and removes clients that
+    /// have disconnected.
+    pub fn ping(&self) {
+        let message = Bytes::from_static(b": ping\n\n");
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain_mut(|client| client.sender.try_send(message.clone()).is_ok());
+        debug!("{} event stream clients connected", clients.len());
+    }
+
+    /// Publishes the current state of a comment after it has been posted,
+    /// changed, deleted or restored. `was_public` is whether the comment was
+    /// shown to readers before the change, a `delete` event is only sent to
+    /// readers if it was.
+    pub async fn comment_changed(
+        &self,
+        pool: &Pool,
+        settings: &Settings,
+        comment: &PrivateComment,
+        was_public: bool,
+    ) -> Result<(), DbError> {
+        let topic = self.get_thread_topic(comment.thread_id, &comment.thread_name);
+        if is_public(comment) {
+            // The parent is only looked up when someone is listening
+            if self.has_subscribers(&topic) {
+                // Replies nested deeper than the max depth of the thread are
+                // shown as replies to their closest visible ancestor
+                let max_depth = match threads::get_thread_by_id(pool, comment.thread_id).await? {
+                    Some(thread) => get_max_depth(&thread, settings),
+                    None => settings.max_depth,
+                };
+                self.publish(&topic, "comment", &PublicComment {
+                    id: comment.id,
+                    parent_id: comments::get_visible_parent_id(pool, comment.id, max_depth).await?,
+                    name: comment.name.clone(),
+                    website: comment.website.clone(),
+                    html: comment.html.clone(),
+                    created: comment.created.clone(),
+                    created_timestamp: comment.created_timestamp,
+                    approved: true,
+                    deleted: false,
+                    hidden: false,
+                    replies: vec![],
+                });
+            }
+        } else if was_public {
+            self.publish(&topic, "delete", &json!({ "id": comment.id }));
+        }
+        self.publish(&Topic::Moderation, "comment", comment);
+        Ok(())
+    }
+
+    /// Publishes the permanent deletion of a comment.
+    pub fn comment_purged(&self, comment: &PrivateComment) {
+        if is_public(comment) {
+            self.publish(&self.get_thread_topic(comment.thread_id, &comment.thread_name), "delete", &json!({ "id": comment.id }));
+        }
+        self.publish(&Topic::Moderation, "delete", &json!({ "id": comment.id }));
+    }
+}
+
+/// Whether a comment is shown to readers of its thread.
+pub fn is_public(comment: &PrivateComment) -> bool {
+    comment.status == CommentStatus::Approved && comment.deleted_at.is_none()
+}
+
+#[get("/threads/{name:.+}/events")]
+async fn get_thread_events(
+    request: web::HttpRequest,
+    web::Path(name): web::Path<String>,
+    pool: web::Data<Pool>,
+    settings: web::Data<Settings>,
+    broadcaster: web::Data<Broadcaster>,
+) -> actix_web::Result<HttpResponse> {
+    let name = normalize_thread_name(&name, &settings);
+    // Threads that don't exist yet are subscribed to by name
+    let topic = match threads::get_thread_by_name_or_alias(&pool, &name).await? {
+        Some(thread) => Topic::Thread(thread.id),
+        None => Topic::NewThread(name),
+    };
+    broadcaster.subscribe(topic, auth::get_client_ip(&request, &settings))
+}
+
+#[get("/admin/events")]
+async fn get_moderation_events(
+    request: web::HttpRequest,
+    pool: web::Data<Pool>,
+    settings: web::Data<Settings>,
+    broadcaster: web::Data<Broadcaster>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    auth::validate_access(request, &pool, Scope::CommentsRead).await?;
+    broadcaster.subscribe(Topic::Moderation, ip)
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_thread_events)
+        .service(get_moderation_events);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_clients_per_ip() {
+        let broadcaster = Broadcaster::new();
+        for _ in 0..MAX_CLIENTS_PER_IP {
+            assert!(broadcaster.subscribe(Topic::Moderation, "127.0.0.1".to_owned()).is_ok());
+        }
+        assert!(broadcaster.subscribe(Topic::Moderation, "127.0.0.1".to_owned()).is_err());
+        assert!(broadcaster.subscribe(Topic::Moderation, "127.0.0.2".to_owned()).is_ok());
+    }
+
+    #[test]
+    fn disconnects_slow_clients() {
+        let broadcaster = Broadcaster::new();
+        let _response = broadcaster.subscribe(Topic::Moderation, "127.0.0.1".to_owned()).unwrap();
+        for _ in 0..CLIENT_BUFFER_SIZE {
+            broadcaster.publish(&Topic::Moderation, "test", &json!({}));
+        }
+        assert_eq!(broadcaster.clients.lock().unwrap().len(), 1);
+        broadcaster.publish(&Topic::Moderation, "test", &json!({}));
+        broadcaster.publish(&Topic::Moderation, "test", &json!({}));
+        assert_eq!(broadcaster.clients.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn moves_new_thread_subscribers_to_thread_topic() {
+        let broadcaster = Broadcaster::new();
+        let _response = broadcaster.subscribe(Topic::NewThread("/a".to_owned()), "127.0.0.1".to_owned()).unwrap();
+        let _response = broadcaster.subscribe(Topic::NewThread("/b".to_owned()), "127.0.0.1".to_owned()).unwrap();
+        assert!(!broadcaster.has_subscribers(&Topic::Thread(1)));
+        assert!(broadcaster.get_thread_topic(1, "/a") == Topic::Thread(1));
+        assert!(broadcaster.has_subscribers(&Topic::Thread(1)));
+        assert!(!broadcaster.has_subscribers(&Topic::NewThread("/a".to_owned())));
+        assert!(broadcaster.has_subscribers(&Topic::NewThread("/b".to_owned())));
+    }
+}
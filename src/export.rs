@@ -0,0 +1,152 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Export of comment threads as static JSON and HTML files
+
+use std::{fs, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+use log::info;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{comment_thread::load_comment_thread, db::{DbError, Pool, threads}, html, settings::Settings};
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("database error")]
+    DbError(#[from] DbError),
+    #[error("IO error")]
+    IoError(#[from] std::io::Error),
+    #[error("JSON error")]
+    JsonError(#[from] serde_json::Error),
+    #[error("template error")]
+    TemplateError(#[from] askama::Error),
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Html,
+    All,
+}
+
+/// Converts a thread name into a relative file path without extension, e.g.
+/// `/blog/my-post` becomes `blog/my-post`. Names that can't be mapped to a path
+/// one-to-one (e.g. `/blog/my post/` or `blog/my-post`) get a short hash of the
+/// name appended so that different threads never share a file.
+fn get_thread_path(name: &str) -> PathBuf {
+    if name == "/" {
+        return PathBuf::from("index");
+    }
+    let mut lossy = !name.starts_with('/') || name.ends_with('/') || name == "/index";
+    let mut path = PathBuf::new();
+    for segment in name.trim_start_matches('/').split("/") {
+        let sanitized: String = segment.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+            .collect();
+        if sanitized != segment {
+            lossy = true;
+        }
+        if sanitized.chars().all(|c| c == '.') {
+            lossy = true;
+            continue;
+        }
+        path.push(sanitized);
+    }
+    if path.as_os_str().is_empty() {
+        path.push("index");
+    }
+    if lossy {
+        let hash = Sha256::digest(name.as_bytes());
+        let mut file_name = path.file_name().unwrap_or_default().to_owned();
+        file_name.push(format!("-{:02x}{:02x}{:02x}{:02x}", hash[0], hash[1], hash[2], hash[3]));
+        path.set_file_name(file_name);
+    }
+    path
+}
+
+fn get_file_name(path: &Path, extension: &str) -> PathBuf {
+    let mut file_name = path.as_os_str().to_owned();
+    file_name.push(".");
+    file_name.push(extension);
+    file_name.into()
+}
+
+fn write_file(path: &Path, extension: &str, contents: &[u8]) -> Result<(), ExportError> {
+    fs::write(get_file_name(path, extension), contents)?;
+    Ok(())
+}
+
+fn remove_file(path: &Path, extension: &str) -> Result<bool, ExportError> {
+    match fs::remove_file(get_file_name(path, extension)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e)?,
+    }
+}
+
+/// Writes the approved comments of each thread to `dir` as JSON (in the same
+/// format as `GET /comments`) and as pre-rendered HTML. Files belonging to
+/// threads that have since been moved to the trash, renamed or merged into
+/// another thread are removed. Returns the number of exported threads.
+pub async fn export_threads(
+    pool: &Pool,
+    settings: &Settings,
+    dir: &Path,
+    since: Option<DateTime<Utc>>,
+    format: ExportFormat,
+    base_url: &str,
+) -> Result<usize, ExportError> {
+    for name in threads::get_removed_thread_names(pool, since).await? {
+        let path = dir.join(get_thread_path(&name));
+        let mut removed = false;
+        for extension in &["json", "html"] {
+            removed |= remove_file(&path, extension)?;
+        }
+        if removed {
+            info!("Removed thread '{}' from {}", name, path.display());
+        }
+    }
+    let threads = threads::get_changed_threads(pool, since).await?;
+    for thread in &threads {
+        let (_, comment_thread) = load_comment_thread(pool, settings, &thread.name, false, None).await?;
+        let path = dir.join(get_thread_path(&thread.name));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if format != ExportFormat::Html {
+            write_file(&path, "json", &serde_json::to_vec_pretty(&comment_thread)?)?;
+        }
+        if format != ExportFormat::Json {
+            let html = html::render_thread_fragment(thread, &comment_thread, base_url)?;
+            write_file(&path, "html", html.as_bytes())?;
+        }
+        info!("Exported thread '{}' to {}", thread.name, path.display());
+    }
+    Ok(threads.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thread_path_keeps_clean_names() {
+        assert_eq!(get_thread_path("/"), PathBuf::from("index"));
+        assert_eq!(get_thread_path("/blog/my-post"), PathBuf::from("blog/my-post"));
+        assert_eq!(get_thread_path("/blog/post.html"), PathBuf::from("blog/post.html"));
+    }
+
+    #[test]
+    fn thread_path_does_not_collide() {
+        let names = ["/blog/post", "/blog_post", "/blog/post/", "blog/post", "/blog post",
+            "/blog?post", "/blog/../post", "/index", "", "/blog//post"];
+        let paths: std::collections::HashSet<PathBuf> = names.iter().map(|name| get_thread_path(name)).collect();
+        assert_eq!(paths.len(), names.len());
+        assert!(get_thread_path("/blog post").to_string_lossy().starts_with("blog_post-"));
+        assert!(!get_thread_path("/blog/../post").to_string_lossy().contains(".."));
+    }
+}
@@ -0,0 +1,312 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Server-side rendered comment threads for readers without JavaScript
+
+use actix_web::{HttpResponse, error, get, http, post, web};
+use askama::Template;
+use chrono::{TimeZone, Utc};
+use serde::Deserialize;
+
+use crate::{NewCommentData, auth, comment_thread::{CommentThread, load_comment_thread}, create_comment, db::{Pool, comments::PublicComment, threads::Thread}, events::Broadcaster, settings::Settings};
+
+#[derive(Deserialize)]
+struct ThreadHtmlQuery {
+    fragment: Option<bool>,
+    newest_first: Option<bool>,
+    author: Option<String>,
+    reply: Option<i32>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CommentFormData {
+    t: String,
+    parent_id: Option<i32>,
+    redirect: Option<String>,
+    name: String,
+    email: String,
+    website: String,
+    content: String,
+}
+
+/// A comment in the flattened comment tree.
+struct CommentItem<'a> {
+    comment: &'a PublicComment,
+    depth: usize,
+    created: String,
+    website: Option<&'a str>,
+}
+
+struct ThreadView<'a> {
+    name: &'a str,
+    title: &'a str,
+    page_url: String,
+    action: String,
+    redirect: String,
+    open: bool,
+    require_name: bool,
+    require_email: bool,
+    count: usize,
+    comments: Vec<CommentItem<'a>>,
+    reply: Option<i32>,
+    error: Option<&'static str>,
+    highlight_css: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "thread.html")]
+struct ThreadPage<'a> {
+    view: ThreadView<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "thread_fragment.html")]
+struct ThreadFragment<'a> {
+    view: ThreadView<'a>,
+}
+
+fn flatten_comments<'a>(comments: &'a [PublicComment], depth: usize, items: &mut Vec<CommentItem<'a>>) {
+    for comment in comments {
+        items.push(CommentItem {
+            comment,
+            depth,
+            website: Some(comment.website.as_str()).filter(|website| is_web_url(website)),
+            created: Utc.timestamp(comment.created_timestamp, 0).format("%Y-%m-%d %H:%M").to_string(),
+        });
+        flatten_comments(&comment.replies, depth + 1, items);
+    }
+}
+
+fn get_error_message(code: &str) -> &'static str {
+    match code {
+        "MISSING_CONTENT" => "Comment cannot be empty",
+        "MISSING_NAME" => "Anonymous comments are not allowed",
+        "MISSING_EMAIL" => "An email is required",
+        "INVALID_WEBSITE" => "The website must be an http or https URL",
+        "TOO_MANY_COMMENTS" => "Too many comments",
+        "TOO_MANY_LINKS" => "Comment contains too many links",
+        "THREAD_LOCKED" | "THREAD_CLOSED" => "This thread is closed for new comments",
+        _ => "An unknown error occurred",
+    }
+}
+
+fn get_thread_view<'a>(
+    name: &'a str,
+    thread: Option<&'a Thread>,
+    comment_thread: &'a CommentThread,
+    base_url: &str,
+    redirect: String,
+) -> ThreadView<'a> {
+    let mut comments = Vec::new();
+    flatten_comments(&comment_thread.comments, 0, &mut comments);
+    ThreadView {
+        name,
+        title: thread.and_then(|t| t.title.as_deref()).unwrap_or(name),
+        page_url: format!("{}/threads/{}.html", base_url, name),
+        action: format!("{}/comments/form", base_url),
+        redirect,
+        open: comment_thread.open,
+        require_name: comment_thread.require_name,
+        require_email: comment_thread.require_email,
+        count: comments.iter().filter(|item| !item.comment.hidden).count(),
+        comments,
+        reply: None,
+        error: None,
+        highlight_css: None,
+    }
+}
+
+/// Renders the embeddable HTML fragment of a thread. `base_url` is the public
+/// URL of the Uncomment server which the comment form is posted to.
+pub fn render_thread_fragment(
+    thread: &Thread,
+    comment_thread: &CommentThread,
+    base_url: &str,
+) -> askama::Result<String> {
+    let redirect = thread.url.clone().unwrap_or_else(|| format!("/threads/{}.html", thread.name));
+    ThreadFragment {
+        view: get_thread_view(&thread.name, Some(thread), comment_thread, base_url, redirect),
+    }.render()
+}
+
+/// Whether a URL submitted as the website of a commenter can be linked to.
+pub fn is_web_url(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    (url.starts_with("http://") || url.starts_with("https://"))
+        && !url.chars().any(|c| c.is_control() || c.is_whitespace())
+}
+
+/// Gets the public URL of the server from `base_url`, falls back to the scheme
+/// and `Host` header of the request when it isn't set.
+pub fn get_base_url(request: &web::HttpRequest, settings: &Settings) -> String {
+    match settings.base_url.as_deref().map(|url| url.trim_end_matches("/")) {
+        Some(url) if !url.is_empty() => url.to_owned(),
+        _ => {
+            let info = request.connection_info();
+            format!("{}://{}", info.scheme(), info.host())
+        },
+    }
+}
+
+/// Only allow redirects to relative paths and to the configured hosts.
+/// Browsers treat backslashes as slashes, so `/\example.com` would be a
+/// protocol-relative URL.
+fn is_allowed_redirect(url: &str, settings: &Settings) -> bool {
+    if url.contains('\\') || url.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return false;
+    }
+    (url.starts_with("/") && !url.starts_with("//"))
+        || settings.host.split(",").any(|host| !host.is_empty() && (url == host || url.starts_with(&format!("{}/", host))))
+}
+
+/// Replaces a query parameter in a URL and removes the fragment.
+fn set_query_param(url: &str, key: &str, value: Option<&str>) -> String {
+    let url = url.split("#").next().unwrap_or(url);
+    let mut parts = url.splitn(2, "?");
+    let path = parts.next().unwrap_or("");
+    let prefix = format!("{}=", key);
+    let mut params: Vec<String> = parts.next().unwrap_or("").split("&")
+        .filter(|param| !param.is_empty() && !param.starts_with(&prefix))
+        .map(|param| param.to_owned())
+        .collect();
+    if let Some(value) = value {
+        params.push(format!("{}{}", prefix, value));
+    }
+    if params.is_empty() {
+        path.to_owned()
+    } else {
+        format!("{}?{}", path, params.join("&"))
+    }
+}
+
+#[get("/threads/{name:.+}.html")]
+async fn get_thread_html(
+    request: web::HttpRequest,
+    web::Path(name): web::Path<String>,
+    query: web::Query<ThreadHtmlQuery>,
+    pool: web::Data<Pool>,
+    settings: web::Data<Settings>,
+) -> actix_web::Result<HttpResponse> {
+    let (thread, comment_thread) = load_comment_thread(&pool, &settings, &name, query.newest_first.unwrap_or(false),
+        query.author.as_deref()).await?;
+    if thread.is_none() && !settings.auto_threads {
+        Err(error::ErrorNotFound("THREAD_NOT_FOUND"))?;
+    }
+    // Embedded fragments redirect back to the page of the thread
+    let redirect = match thread.as_ref().and_then(|t| t.url.as_ref()) {
+        Some(url) if query.fragment.unwrap_or(false) => url.clone(),
+        _ => set_query_param(&set_query_param(&request.uri().to_string(), "error", None), "author", None),
+    };
+    let base_url = get_base_url(&request, &settings);
+    let mut view = get_thread_view(&name, thread.as_ref(), &comment_thread, &base_url, redirect);
+    view.reply = query.reply;
+    view.error = query.error.as_deref().map(get_error_message);
+    if settings.syntax_highlighting {
+        view.highlight_css = Some(format!("{}/highlight.css", base_url));
+    }
+    let html = if query.fragment.unwrap_or(false) {
+        ThreadFragment { view }.render()
+    } else {
+        ThreadPage { view }.render()
+    }.map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(html))
+}
+
+/// Form-encoded variant of `POST /comments` which redirects back to the page
+/// the form was posted from.
+#[post("/comments/form")]
+async fn post_comment_form(
+    request: web::HttpRequest,
+    data: web::Form<CommentFormData>,
+    pool: web::Data<Pool>,
+    settings: web::Data<Settings>,
+    events: web::Data<Broadcaster>,
+) -> actix_web::Result<HttpResponse> {
+    let redirect = match &data.redirect {
+        Some(redirect) if is_allowed_redirect(redirect, &settings) => redirect.clone(),
+        _ => format!("/threads/{}.html", data.t),
+    };
+    let ip = auth::get_client_ip(&request, &settings);
+    let comment = NewCommentData {
+        name: data.name.clone(),
+        email: data.email.clone(),
+        website: data.website.clone(),
+        content: data.content.clone(),
+    };
+    let location = match create_comment(&pool, &settings, ip, &data.t, data.parent_id, &comment, &events).await {
+        Ok(posted) => {
+            // Pending comments are only shown to their author
+            let author = if posted.comment.approved { None } else { Some(posted.author_token.as_str()) };
+            let redirect = set_query_param(&redirect, "reply", None);
+            format!("{}#comment-{}", set_query_param(&redirect, "author", author), posted.comment.id)
+        },
+        Err(err) if err.as_response_error().status_code().is_client_error() => {
+            format!("{}#comment-form", set_query_param(&redirect, "error", Some(&err.to_string())))
+        },
+        Err(err) => return Err(err),
+    };
+    Ok(HttpResponse::SeeOther().header(http::header::LOCATION, location).finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_thread_html)
+        .service(post_comment_form);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_configured_base_url() {
+        let mut settings = Settings::for_tests();
+        let request = actix_web::test::TestRequest::default()
+            .header("Host", "evil.com")
+            .to_http_request();
+        assert_eq!(get_base_url(&request, &settings), "http://evil.com");
+        settings.base_url = Some("https://comments.example.com/".to_owned());
+        assert_eq!(get_base_url(&request, &settings), "https://comments.example.com");
+    }
+
+    #[test]
+    fn allows_relative_redirects() {
+        let settings = Settings::for_tests();
+        assert!(is_allowed_redirect("/blog/post", &settings));
+        assert!(is_allowed_redirect("/blog/post?a=b#comment-1", &settings));
+        assert!(is_allowed_redirect("https://example.com/blog/post", &settings));
+        assert!(is_allowed_redirect("https://example.com", &settings));
+    }
+
+    #[test]
+    fn rejects_redirects_to_other_hosts() {
+        let settings = Settings::for_tests();
+        assert!(!is_allowed_redirect("//evil.com", &settings));
+        assert!(!is_allowed_redirect("/\\evil.com", &settings));
+        assert!(!is_allowed_redirect("/\t/evil.com", &settings));
+        assert!(!is_allowed_redirect("https://evil.com", &settings));
+        assert!(!is_allowed_redirect("https://example.com.evil.com/", &settings));
+        assert!(!is_allowed_redirect("https://example.com@evil.com/", &settings));
+        assert!(!is_allowed_redirect("javascript:alert(1)", &settings));
+    }
+
+    #[test]
+    fn sets_query_params() {
+        assert_eq!(set_query_param("/post", "reply", Some("1")), "/post?reply=1");
+        assert_eq!(set_query_param("/post?a=b&reply=2#x", "reply", Some("1")), "/post?a=b&reply=1");
+        assert_eq!(set_query_param("/post?reply=2", "reply", None), "/post");
+        assert_eq!(set_query_param("/post?replying=2", "reply", None), "/post?replying=2");
+    }
+
+    #[test]
+    fn accepts_only_web_urls() {
+        assert!(is_web_url("https://example.com"));
+        assert!(is_web_url("HTTP://example.com/a"));
+        assert!(!is_web_url("javascript:alert(1)"));
+        assert!(!is_web_url(" javascript:alert(1)"));
+        assert!(!is_web_url("data:text/html,x"));
+        assert!(!is_web_url("example.com"));
+    }
+}
@@ -7,7 +7,7 @@
 
 use std::{collections::HashMap, fs::File, io::BufReader};
 
-use crate::db::{DbError, Pool, comments::{self, CommentPosition}, threads};
+use crate::{db::{DbError, Pool, comments::{self, CommentPosition}, threads}, normalize::normalize_thread_name, render, settings::Settings};
 use chrono::{DateTime, Utc};
 use log::info;
 use minidom::{Element, NSChoice};
@@ -134,11 +134,12 @@ pub fn read_xml_comments(
 
 async fn insert_imported_comment(
     pool: &Pool,
+    settings: &Settings,
     thread_id: i32,
     parent: Option<&CommentPosition>,
     comment: &ImportComment,
 ) -> Result<CommentPosition, DbError> {
-    let safe_html = ammonia::clean(&comment.message);
+    let safe_html = render::sanitize_html(&comment.message, settings);
     comments::insert_comment(pool, thread_id, parent, &comments::NewComment {
         name: comment.name.clone(),
         email: "".to_owned(),
@@ -148,19 +149,23 @@ async fn insert_imported_comment(
         markdown: comment.message.clone(),
         status: comments::CommentStatus::Approved,
         created: comment.created,
+        imported: true,
     }).await
 }
 
 pub async fn insert_imported_comments(
     pool: &Pool,
+    settings: &Settings,
     threads: Vec<ImportThread>,
 ) -> Result<(), DbError> {
     for thread in threads {
-        let thread_id = match threads::get_thread_by_name(pool, &thread.name).await? {
+        let name = normalize_thread_name(&thread.name, settings);
+        let thread_id = match threads::get_thread_by_name_or_alias(pool, &name).await? {
             Some(t) => Ok(t),
             None => threads::create_thread(pool, threads::NewThread {
-                name: thread.name,
+                name,
                 title: Some(thread.title),
+                url: None,
             }).await,
         }?.id;
         let mut queue: Vec<(&ImportComment, Option<CommentPosition>)> = Vec::new();
@@ -168,7 +173,7 @@ pub async fn insert_imported_comments(
             queue.push((comment, None));
         }
         while let Some((comment, parent)) = queue.pop() {
-            let position = insert_imported_comment(pool, thread_id, parent.as_ref(), &comment).await?;
+            let position = insert_imported_comment(pool, settings, thread_id, parent.as_ref(), &comment).await?;
             for reply in comment.replies.iter() {
                 queue.push((reply, Some(position)));
             }
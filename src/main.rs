@@ -5,21 +5,35 @@
 
 //! Uncomment server
 
-use actix_web::{App, HttpResponse, HttpServer, ResponseError, client::Client, error, get, post, web};
+use actix_web::{App, HttpResponse, HttpServer, ResponseError, error, get, post, rt, web};
 use chrono::{Duration, Utc};
-use db::{DbError, Pool, comments::{self, CommentStatus, NewComment}, threads::{self, NewThread}};
+use clap::Parser;
+use db::{DbError, Pool, attachments::link_attachments, comments::{self, CommentStatus, NewComment, PublicComment}, mentions, threads::{self, NewThread, ThreadSort}};
 use dotenv::dotenv;
-use log::{debug, info};
-use pulldown_cmark::Parser;
-use serde::Deserialize;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 
-use crate::settings::Settings;
+use std::{cmp, collections::HashMap};
+
+use crate::{comment_thread::{get_max_depth, is_auto_closed, load_comment_thread}, events::Broadcaster, normalize::normalize_thread_name, rate_limit::RateLimiter, settings::Settings, storage::Storage};
 
 mod db;
 mod auth;
 mod admin;
 mod settings;
 mod import;
+mod export;
+mod cli;
+mod html;
+mod events;
+mod rate_limit;
+mod render;
+mod rerender;
+mod metadata;
+mod normalize;
+mod attachments;
+mod storage;
+mod comment_thread;
 
 #[derive(Deserialize)]
 struct CountQuery {
@@ -31,6 +45,18 @@ struct CommentQuery {
     t: String,
     parent_id: Option<i32>,
     newest_first: Option<bool>,
+    author: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RecentQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ThreadListQuery {
+    sort: Option<ThreadSort>,
+    limit: Option<usize>,
 }
 
 #[derive(Deserialize)]
@@ -41,6 +67,25 @@ struct NewCommentData {
     content: String,
 }
 
+#[derive(Deserialize)]
+struct PreviewData {
+    /// Thread used for resolving mentions and references
+    t: Option<String>,
+    content: String,
+}
+
+#[derive(Serialize)]
+struct Preview {
+    html: String,
+}
+
+#[derive(Serialize)]
+struct PostedComment {
+    #[serde(flatten)]
+    comment: PublicComment,
+    author_token: String,
+}
+
 impl ResponseError for DbError {
 }
 
@@ -48,10 +93,21 @@ impl ResponseError for DbError {
 async fn count_comments(
     query: web::Query<CountQuery>,
     pool: web::Data<Pool>,
+    settings: web::Data<Settings>,
 ) -> actix_web::Result<HttpResponse> {
-    let thread_names: Vec<&str> = query.t.split(",").collect();
+    let names: Vec<(&str, String)> = query.t.split(",")
+        .map(|name| (name, normalize_thread_name(name, &settings)))
+        .collect();
+    let aliases = threads::resolve_thread_aliases(&pool, names.iter().map(|(_, name)| name.as_str()).collect()).await?;
+    let thread_names: Vec<&str> = names.iter()
+        .map(|(_, name)| aliases.get(name).unwrap_or(name).as_str())
+        .collect();
     let counts = comments::count_comments_by_thread(&pool, thread_names).await?;
-    Ok(HttpResponse::Ok().json(counts))
+    // The counts are returned using the thread names given by the client
+    let result: HashMap<&str, i64> = names.iter()
+        .filter_map(|(requested, name)| counts.get(aliases.get(name).unwrap_or(name)).map(|count| (*requested, *count)))
+        .collect();
+    Ok(HttpResponse::Ok().json(result))
 }
 
 #[get("/comments")]
@@ -61,50 +117,66 @@ async fn get_comments(
     settings: web::Data<Settings>,
 ) -> actix_web::Result<HttpResponse> {
     debug!("comments requested for {}", query.t);
-    let comments = comments::get_comment_thread(&pool, &query.t, query.newest_first.unwrap_or(false), settings.max_depth)
-        .await?;
-    Ok(HttpResponse::Ok().json(comments))
+    let (_, comment_thread) = load_comment_thread(&pool, &settings, &query.t, query.newest_first.unwrap_or(false),
+        query.author.as_deref()).await?;
+    Ok(HttpResponse::Ok().json(comment_thread))
 }
 
-#[post("/comments")]
-async fn post_comment(
-    request: web::HttpRequest,
-    query: web::Query<CommentQuery>,
-    data: web::Json<NewCommentData>,
+#[get("/recent")]
+async fn get_recent_comments(
+    query: web::Query<RecentQuery>,
     pool: web::Data<Pool>,
-    settings: web::Data<Settings>,
 ) -> actix_web::Result<HttpResponse> {
-    let ip = match settings.forwarded {
-        true => request.connection_info().realip_remote_addr().unwrap_or("").to_owned(),
-        false => request.peer_addr().map(|a| a.ip().to_string()).unwrap_or("".to_owned()),
-    };
+    let limit = cmp::min(50, query.limit.unwrap_or(10));
+    Ok(HttpResponse::Ok().json(comments::get_recent_comments(&pool, limit).await?))
+}
+
+#[get("/threads")]
+async fn get_threads(
+    query: web::Query<ThreadListQuery>,
+    pool: web::Data<Pool>,
+) -> actix_web::Result<HttpResponse> {
+    let limit = cmp::min(50, query.limit.unwrap_or(10));
+    let sort = query.sort.unwrap_or(ThreadSort::Active);
+    Ok(HttpResponse::Ok().json(threads::get_public_threads(&pool, sort, limit).await?))
+}
+
+/// Validates and stores a new comment, creating the thread if necessary.
+async fn create_comment(
+    pool: &Pool,
+    settings: &Settings,
+    ip: String,
+    name: &str,
+    parent_id: Option<i32>,
+    data: &NewCommentData,
+    events: &Broadcaster,
+) -> actix_web::Result<PostedComment> {
     if settings.rate_limit > 0 {
-        let count = comments::count_comments_by_ip(&pool, &ip, Utc::now() - Duration::minutes(settings.rate_limit_interval)).await?;
+        let count = comments::count_comments_by_ip(pool, &ip, Utc::now() - Duration::minutes(settings.rate_limit_interval)).await?;
         info!("rate limit: {} / {} comments in the past {} minutes", count, settings.rate_limit,
             settings.rate_limit_interval);
         if count >= settings.rate_limit {
             Err(error::ErrorTooManyRequests("TOO_MANY_COMMENTS"))?;
         }
     }
-    let thread = match threads::get_thread_by_name(&pool, &query.t).await? {
+    let thread_name = normalize_thread_name(name, settings);
+    let thread = match threads::get_thread_by_name_or_alias(pool, &thread_name).await? {
+        Some(t) if t.deleted_at.is_some() => Err(error::ErrorBadRequest("THREAD_NOT_FOUND")),
         Some(t) => Ok(t),
         None => {
             if settings.auto_threads {
+                let mut new_thread = NewThread {
+                    name: thread_name.clone(),
+                    title: None,
+                    url: None,
+                };
                 if let Some(thread_url) = &settings.thread_url {
-                    let client = Client::default();
-                    let response = client.get(thread_url.replace("%name%", &query.t))
-                        .send()
-                        .await?;
-                    info!("Received thread url status {}", response.status());
-                    if !response.status().is_success() {
-                        Err(error::ErrorBadRequest("THREAD_NOT_FOUND"))?;
-                    }
-                    // TODO: title detection
+                    let metadata = metadata::fetch_metadata(&thread_url.replace("%name%", &thread_name)).await?
+                        .ok_or_else(|| error::ErrorBadRequest("THREAD_NOT_FOUND"))?;
+                    new_thread.title = metadata.title;
+                    new_thread.url = metadata.url;
                 }
-                Ok(threads::create_thread(&pool, NewThread {
-                    name: query.t.clone(),
-                    title: None,
-                }).await.map(|t| {
+                Ok(threads::create_thread(pool, new_thread).await.map(|t| {
                     info!("Created new thread: '{}' (id: {})", t.name, t.id);
                     t
                 })?)
@@ -113,10 +185,16 @@ async fn post_comment(
             }
         },
     }?;
-    let parent = match query.parent_id {
+    if thread.locked {
+        Err(error::ErrorBadRequest("THREAD_LOCKED"))?;
+    }
+    if !thread.is_open() || is_auto_closed(pool, &thread, settings).await? {
+        Err(error::ErrorBadRequest("THREAD_CLOSED"))?;
+    }
+    let parent = match parent_id {
         Some(id) => {
-            comments::get_comment_position(&pool, id).await?
-                .filter(|pos| pos.thread_id == thread.id && pos.status == CommentStatus::Approved)
+            comments::get_comment_position(pool, id).await?
+                .filter(|pos| pos.thread_id == thread.id && pos.status == CommentStatus::Approved && !pos.deleted)
                 .ok_or_else(|| error::ErrorBadRequest("PARENT_NOT_FOUND"))
                 .map(|pos| {
                     debug!("Repying to comment {} in thread {}", pos.id, thread.id);
@@ -131,27 +209,104 @@ async fn post_comment(
     if data.content.is_empty() {
         Err(error::ErrorBadRequest("MISSING_CONTENT"))?;
     }
-    if settings.require_name && data.name.is_empty() {
+    if thread.require_name.unwrap_or(settings.require_name) && data.name.is_empty() {
         Err(error::ErrorBadRequest("MISSING_NAME"))?;
     }
-    if settings.require_email && data.email.is_empty() {
+    if thread.require_email.unwrap_or(settings.require_email) && data.email.is_empty() {
         Err(error::ErrorBadRequest("MISSING_EMAIL"))?;
     }
-    let parser = Parser::new(data.content.as_str());
-    let mut unsafe_html = String::new();
-    pulldown_cmark::html::push_html(&mut unsafe_html, parser);
-    let safe_html = ammonia::clean(&unsafe_html);
-    let comment = comments::post_comment(&pool, thread.id, parent.as_ref(), settings.max_depth, NewComment {
+    if !data.website.is_empty() && !html::is_web_url(&data.website) {
+        Err(error::ErrorBadRequest("INVALID_WEBSITE"))?;
+    }
+    let references = render::ThreadReferences::new(mentions::get_thread_commenters(pool, thread.id, None).await?);
+    let rendered = render::render_comment(&data.content, settings, &references);
+    if settings.max_links > 0 && render::count_links(&rendered.html) > settings.max_links {
+        Err(error::ErrorBadRequest("TOO_MANY_LINKS"))?;
+    }
+    let moderate = thread.moderate_all.unwrap_or(settings.moderate_all);
+    let comment = comments::post_comment(pool, thread.id, parent.as_ref(), get_max_depth(&thread, settings), NewComment {
         name: data.name.clone(),
         email: data.email.clone(),
         website: data.website.clone(),
         ip,
         markdown: data.content.clone(),
-        html: safe_html,
-        status: if settings.moderate_all { CommentStatus::Pending } else { CommentStatus::Approved },
+        html: rendered.html,
+        status: if moderate { CommentStatus::Pending } else { CommentStatus::Approved },
         created: Utc::now(),
+        imported: false,
     }).await?;
-    Ok(HttpResponse::Ok().json(comment))
+    mentions::set_mentions(pool, comment.id, &rendered.mentions).await?;
+    if settings.attachments {
+        link_attachments(pool, comment.id, attachments::find_attachment_keys(&data.content)).await?;
+    }
+    if let Some(private_comment) = comments::get_comment(pool, comment.id).await? {
+        events.comment_changed(pool, settings, &private_comment, false).await?;
+    }
+    let author_token = auth::sign_author_token(comment.id, settings);
+    Ok(PostedComment { comment, author_token })
+}
+
+#[post("/comments")]
+async fn post_comment(
+    request: web::HttpRequest,
+    query: web::Query<CommentQuery>,
+    data: web::Json<NewCommentData>,
+    pool: web::Data<Pool>,
+    settings: web::Data<Settings>,
+    events: web::Data<Broadcaster>,
+) -> actix_web::Result<HttpResponse> {
+    let ip = auth::get_client_ip(&request, &settings);
+    let posted = create_comment(&pool, &settings, ip, &query.t, query.parent_id, &data, &events).await?;
+    Ok(HttpResponse::Ok().json(posted))
+}
+
+/// Renders markdown the same way as when a comment is posted.
+#[post("/preview")]
+async fn preview_comment(
+    request: web::HttpRequest,
+    data: web::Json<PreviewData>,
+    pool: web::Data<Pool>,
+    settings: web::Data<Settings>,
+    limiter: web::Data<RateLimiter>,
+) -> actix_web::Result<HttpResponse> {
+    if !limiter.check(&auth::get_client_ip(&request, &settings)) {
+        Err(error::ErrorTooManyRequests("TOO_MANY_REQUESTS"))?;
+    }
+    let thread = match &data.t {
+        Some(name) => threads::get_thread_by_name_or_alias(&pool, &normalize_thread_name(name, &settings)).await?,
+        None => None,
+    };
+    let references = match thread {
+        Some(thread) => render::ThreadReferences::new(mentions::get_thread_commenters(&pool, thread.id, None).await?),
+        None => render::ThreadReferences::default(),
+    };
+    let rendered = render::render_comment(&data.content, &settings, &references);
+    Ok(HttpResponse::Ok().json(Preview { html: rendered.html }))
+}
+
+/// Stylesheet for highlighted code blocks in comments.
+#[get("/highlight.css")]
+async fn get_highlight_css(
+    settings: web::Data<Settings>,
+) -> actix_web::Result<HttpResponse> {
+    let css = render::get_highlight_css(&settings.highlight_theme)
+        .ok_or_else(|| error::ErrorNotFound("THEME_NOT_FOUND"))?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/css; charset=utf-8")
+        .header("Cache-Control", "public, max-age=86400")
+        .body(css))
+}
+
+/// Permanently deletes threads and comments that have been in the trash for
+/// longer than the retention period.
+async fn purge_trash(pool: &Pool, retention: i64) -> Result<(), DbError> {
+    let before = Utc::now() - Duration::days(retention);
+    let threads = threads::purge_deleted_threads(pool, before).await?;
+    let comments = comments::purge_deleted_comments(pool, before).await?;
+    if threads > 0 || comments > 0 {
+        info!("Purged {} threads and {} comments from the trash", threads, comments);
+    }
+    Ok(())
 }
 
 #[actix_web::main]
@@ -159,16 +314,93 @@ async fn main() -> std::io::Result<()> {
     dotenv().ok();
     env_logger::init();
 
-    info!("Starting uncomment server...");
+    let cli = cli::Cli::parse();
 
     let settings = Settings::new().unwrap();
 
+    if !render::check_sanitizer(&settings) {
+        eprintln!("Invalid sanitizer settings, check UNCOMMENT_ALLOWED_TAGS and UNCOMMENT_ALLOWED_ATTRIBUTES");
+        std::process::exit(1);
+    }
+
     let pool: Pool = db::install(&settings).await.unwrap();
 
+    match cli.command {
+        None | Some(cli::Command::Serve) => serve(pool, settings).await,
+        Some(cli::Command::Admin(command)) => {
+            if let Err(err) = cli::run(command, &pool, &settings).await {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            Ok(())
+        },
+    }
+}
+
+async fn serve(pool: Pool, settings: Settings) -> std::io::Result<()> {
+    info!("Starting uncomment server...");
+
     auth::install(&pool, &settings).await.unwrap();
 
     auth::cleanup(&pool).await.unwrap();
 
+    if settings.trash_retention > 0 {
+        let pool = pool.clone();
+        let retention = settings.trash_retention;
+        rt::spawn(async move {
+            loop {
+                if let Err(err) = purge_trash(&pool, retention).await {
+                    warn!("Unable to purge trash: {}", err);
+                }
+                rt::time::delay_for(std::time::Duration::from_secs(3600)).await;
+            }
+        });
+    }
+
+    let events = web::Data::new(Broadcaster::new());
+    {
+        let events = events.clone();
+        rt::spawn(async move {
+            loop {
+                rt::time::delay_for(std::time::Duration::from_secs(30)).await;
+                events.ping();
+            }
+        });
+    }
+
+    let preview_limiter = web::Data::new(RateLimiter::new(settings.preview_rate_limit,
+        std::time::Duration::from_secs(60)));
+    {
+        let preview_limiter = preview_limiter.clone();
+        rt::spawn(async move {
+            loop {
+                rt::time::delay_for(std::time::Duration::from_secs(600)).await;
+                preview_limiter.cleanup();
+            }
+        });
+    }
+
+    let storage = if settings.attachments {
+        let storage = Storage::new(&settings)
+            .map(web::Data::new)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("invalid attachment storage: {}", err)))?;
+        Some(storage)
+    } else {
+        None
+    };
+    if let Some(storage) = storage.clone() {
+        let pool = pool.clone();
+        let retention = settings.trash_retention;
+        rt::spawn(async move {
+            loop {
+                if let Err(err) = attachments::cleanup_attachments(&pool, &storage, retention).await {
+                    warn!("Unable to clean up attachments: {}", err);
+                }
+                rt::time::delay_for(std::time::Duration::from_secs(3600)).await;
+            }
+        });
+    }
+
     let address = settings.listen.clone();
 
     HttpServer::new(move || {
@@ -179,15 +411,27 @@ async fn main() -> std::io::Result<()> {
         for host in settings.host.split(",") {
             cors = cors.allowed_origin(host);
         }
-        App::new()
+        let mut app = App::new()
             .wrap(cors)
             .data(pool.clone())
             .data(settings.clone())
-            .service(count_comments)
+            .app_data(events.clone())
+            .app_data(preview_limiter.clone());
+        if let Some(storage) = &storage {
+            app = app.app_data(storage.clone());
+        }
+        app.service(count_comments)
             .service(get_comments)
             .service(post_comment)
+            .service(preview_comment)
+            .service(get_highlight_css)
+            .service(get_recent_comments)
+            .service(get_threads)
             .configure(auth::config)
             .configure(admin::config)
+            .configure(html::config)
+            .configure(events::config)
+            .configure(attachments::config)
             .service(actix_files::Files::new("/", "dist").index_file("index.html"))
     })
     .bind(address)?
@@ -0,0 +1,214 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Thread metadata detection
+
+use std::{collections::HashMap, net::IpAddr};
+
+use actix_web::{client::Client, error, http::Uri};
+use async_std::net::ToSocketAddrs;
+use futures::StreamExt;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::settings::Settings;
+
+/// Maximum number of bytes to read from a page when detecting metadata. The
+/// rest of the page is ignored.
+const MAX_PAGE_SIZE: usize = 1024 * 1024;
+
+static TAG_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)<(meta|link)\s[^>]*>").unwrap());
+
+static ATTRIBUTE_PATTERN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"\s([A-Za-z_:][-A-Za-z0-9_:.]*)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap()
+});
+
+static TITLE_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap());
+
+pub struct PageMetadata {
+    pub title: Option<String>,
+    pub url: Option<String>,
+}
+
+/// Gets the origin (scheme, host and port) of a URL in lowercase.
+fn get_origin(url: &str) -> Option<(String, String, u16)> {
+    let uri: Uri = url.trim().parse().ok()?;
+    let scheme = uri.scheme_str()?.to_ascii_lowercase();
+    let port = uri.port_u16().unwrap_or(if scheme == "https" { 443 } else { 80 });
+    Some((scheme, uri.host()?.to_ascii_lowercase(), port))
+}
+
+/// Whether an address can be reached from the internet, i.e. isn't a loopback,
+/// private, link-local or otherwise reserved address.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_documentation() || octets[0] == 0
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64))
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback() || ip.is_unspecified() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+            },
+        },
+    }
+}
+
+/// Checks that a page can be fetched on behalf of an admin: the URL must be on
+/// one of the sites in `host` and must not resolve to a private or loopback
+/// address.
+pub async fn check_page_url(url: &str, settings: &Settings) -> actix_web::Result<()> {
+    let (scheme, host, port) = get_origin(url)
+        .filter(|(scheme, _, _)| scheme == "http" || scheme == "https")
+        .ok_or_else(|| error::ErrorBadRequest("INVALID_THREAD_URL"))?;
+    let allowed = settings.host.split(",")
+        .filter_map(get_origin)
+        .any(|origin| origin == (scheme.clone(), host.clone(), port));
+    if !allowed {
+        Err(error::ErrorBadRequest("THREAD_URL_NOT_ALLOWED"))?;
+    }
+    let addresses: Vec<_> = (host.trim_start_matches('[').trim_end_matches(']'), port).to_socket_addrs().await
+        .map_err(|_| error::ErrorBadRequest("PAGE_NOT_FOUND"))?
+        .collect();
+    if addresses.is_empty() || addresses.iter().any(|address| !is_public_ip(address.ip())) {
+        Err(error::ErrorBadRequest("THREAD_URL_NOT_ALLOWED"))?;
+    }
+    Ok(())
+}
+
+/// Downloads a page and extracts its metadata. Returns `None` if the page
+/// doesn't exist.
+pub async fn fetch_metadata(url: &str) -> actix_web::Result<Option<PageMetadata>> {
+    let client = Client::default();
+    let mut response = client.get(url)
+        .send()
+        .await?;
+    info!("Received thread url status {}", response.status());
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.next().await {
+        match chunk {
+            Ok(chunk) => body.extend_from_slice(&chunk),
+            Err(err) => {
+                warn!("Unable to read thread url body: {}", err);
+                break;
+            },
+        }
+        if body.len() >= MAX_PAGE_SIZE {
+            body.truncate(MAX_PAGE_SIZE);
+            break;
+        }
+    }
+    let html = String::from_utf8_lossy(&body);
+    Ok(Some(parse_metadata(&html)))
+}
+
+/// Gets the attributes of an HTML tag with lowercase names. The first
+/// occurrence of an attribute is used.
+fn get_attributes(tag: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    for captures in ATTRIBUTE_PATTERN.captures_iter(tag) {
+        let value = captures.get(2).or_else(|| captures.get(3)).map(|m| m.as_str()).unwrap_or("");
+        attributes.entry(captures[1].to_ascii_lowercase()).or_insert_with(|| decode_entities(value));
+    }
+    attributes
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#039;", "'")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Finds the title (preferring `og:title` over `<title>`) and canonical URL of
+/// an HTML page.
+pub fn parse_metadata(html: &str) -> PageMetadata {
+    let mut og_title = None;
+    let mut url = None;
+    for tag in TAG_PATTERN.find_iter(html) {
+        let mut attributes = get_attributes(tag.as_str());
+        if og_title.is_none() && attributes.get("property").map(|p| p == "og:title").unwrap_or(false) {
+            og_title = attributes.remove("content");
+        } else if url.is_none() && attributes.get("rel").map(|r| r.eq_ignore_ascii_case("canonical")).unwrap_or(false) {
+            url = attributes.remove("href");
+        }
+    }
+    let title = og_title.or_else(|| {
+        TITLE_PATTERN
+            .captures(html)
+            .and_then(|captures| captures.get(1))
+            .map(|m| decode_entities(m.as_str().trim()))
+    });
+    PageMetadata {
+        title: title.filter(|t| !t.is_empty()),
+        url: url.filter(|u| !u.is_empty()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_origins() {
+        assert_eq!(get_origin("https://Example.com/blog/post"), Some(("https".to_owned(), "example.com".to_owned(), 443)));
+        assert_eq!(get_origin("http://example.com:8080"), Some(("http".to_owned(), "example.com".to_owned(), 8080)));
+        assert_eq!(get_origin("/blog/post"), None);
+    }
+
+    #[test]
+    fn rejects_private_ips() {
+        for ip in &["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0",
+            "100.64.0.1", "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_public_ip("93.184.216.34".parse().unwrap()));
+        assert!(is_public_ip("2606:2800:220:1::".parse().unwrap()));
+    }
+
+    #[async_std::test]
+    async fn only_fetches_pages_on_allowed_hosts() {
+        let mut settings = Settings::for_tests();
+        assert!(check_page_url("https://other.com/post", &settings).await.is_err());
+        assert!(check_page_url("http://example.com/post", &settings).await.is_err());
+        settings.host = "http://localhost:8080".to_owned();
+        assert!(check_page_url("http://localhost:8080/post", &settings).await.is_err());
+    }
+
+    #[test]
+    fn prefers_og_title() {
+        let metadata = parse_metadata(r#"<html><head><title>Page</title>
+            <META property="og:title" content="Post &amp; more">
+            <link href='https://example.com/post' REL="Canonical"/></head></html>"#);
+        assert_eq!(metadata.title.as_deref(), Some("Post & more"));
+        assert_eq!(metadata.url.as_deref(), Some("https://example.com/post"));
+    }
+
+    #[test]
+    fn falls_back_to_title_element() {
+        let metadata = parse_metadata("<title>\n  Page &lt;1&gt;\n</title><meta name=\"og:title\" content=\"\">");
+        assert_eq!(metadata.title.as_deref(), Some("Page <1>"));
+        assert_eq!(metadata.url, None);
+    }
+
+    #[test]
+    fn handles_pages_without_metadata() {
+        let metadata = parse_metadata("<p>Hello</p>");
+        assert_eq!(metadata.title, None);
+        assert_eq!(metadata.url, None);
+    }
+}
@@ -0,0 +1,70 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Thread name normalization
+
+use crate::settings::Settings;
+
+/// Normalizes a thread name using the steps enabled in
+/// `thread_normalization`. The steps are always applied in the same order
+/// regardless of the order they are listed in.
+pub fn normalize_thread_name(name: &str, settings: &Settings) -> String {
+    let steps: Vec<&str> = settings.thread_normalization.split(",").map(|step| step.trim()).collect();
+    let mut name = name.to_owned();
+    if steps.contains(&"fragment") {
+        if let Some(i) = name.find('#') {
+            name.truncate(i);
+        }
+    }
+    if steps.contains(&"query") {
+        if let Some(i) = name.find('?') {
+            name.truncate(i);
+        }
+    }
+    if steps.contains(&"leading_slash") && !name.starts_with('/') {
+        name.insert(0, '/');
+    }
+    if steps.contains(&"trailing_slash") {
+        while name.len() > 1 && name.ends_with('/') {
+            name.pop();
+        }
+    }
+    if steps.contains(&"lowercase") {
+        name = name.to_lowercase();
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(name: &str, steps: &str) -> String {
+        let mut settings = Settings::for_tests();
+        settings.thread_normalization = steps.to_owned();
+        normalize_thread_name(name, &settings)
+    }
+
+    #[test]
+    fn keeps_name_without_steps() {
+        assert_eq!(normalize("Blog/Post/?a=b#c", ""), "Blog/Post/?a=b#c");
+    }
+
+    #[test]
+    fn applies_steps_in_fixed_order() {
+        let steps = "lowercase, trailing_slash, leading_slash, query, fragment";
+        assert_eq!(normalize("Blog/Post/?a=b#c", steps), "/blog/post");
+        assert_eq!(normalize("/blog/post/", steps), "/blog/post");
+        assert_eq!(normalize("/blog/post#comments", steps), "/blog/post");
+    }
+
+    #[test]
+    fn keeps_root() {
+        assert_eq!(normalize("/", "trailing_slash"), "/");
+        assert_eq!(normalize("//", "trailing_slash"), "/");
+        assert_eq!(normalize("", "leading_slash,trailing_slash"), "/");
+        assert_eq!(normalize("?page=2", "query,leading_slash"), "/");
+    }
+}
@@ -0,0 +1,47 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! In-memory rate limiting of requests that aren't stored in the database
+
+use std::{collections::{HashMap, VecDeque}, sync::Mutex, time::{Duration, Instant}};
+
+pub struct RateLimiter {
+    limit: usize,
+    interval: Duration,
+    requests: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: usize, interval: Duration) -> RateLimiter {
+        RateLimiter { limit, interval, requests: Mutex::new(HashMap::new()) }
+    }
+
+    /// Records a request from the given key (e.g. an IP address). Returns
+    /// false if the key has exceeded the limit within the interval.
+    pub fn check(&self, key: &str) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        let times = requests.entry(key.to_owned()).or_insert_with(VecDeque::new);
+        while times.front().map(|time| now.duration_since(*time) >= self.interval).unwrap_or(false) {
+            times.pop_front();
+        }
+        if times.len() >= self.limit {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+    /// Forgets keys that haven't made any requests within the interval.
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        self.requests.lock().unwrap().retain(|_, times| {
+            times.back().map(|time| now.duration_since(*time) < self.interval).unwrap_or(false)
+        });
+    }
+}
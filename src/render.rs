@@ -0,0 +1,420 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Markdown rendering and HTML sanitization of comments
+
+use std::{collections::{HashMap, HashSet}, sync::Mutex};
+
+use once_cell::sync::Lazy;
+use pulldown_cmark::{CodeBlockKind, Event, LinkType, Options, Parser, Tag};
+use regex::Regex;
+use syntect::{highlighting::ThemeSet, html::{ClassStyle, ClassedHTMLGenerator, css_for_theme_with_class_style}, parsing::SyntaxSet, util::LinesWithEndings};
+
+use crate::{db::mentions::{MentionKind, NewMention}, settings::Settings};
+
+/// All highlighting classes are prefixed so that they can be told apart from
+/// other classes when sanitizing.
+const CLASS_PREFIX: &str = "hl-";
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: CLASS_PREFIX };
+
+/// Tags that ammonia removes along with their content. They can't be allowed
+/// or given attributes.
+const CLEAN_CONTENT_TAGS: [&str; 2] = ["script", "style"];
+
+static SYNTAX_SET: Lazy<SyntaxSet> = Lazy::new(SyntaxSet::load_defaults_newlines);
+
+/// Generated stylesheets by theme name.
+static HIGHLIGHT_CSS: Lazy<Mutex<HashMap<String, Option<String>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static LINK_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"https?://[^\s<>]+|@[\w.-]*\w|#c\d+\b").unwrap());
+
+/// Earlier comments in a thread that a comment can mention by the name of the
+/// commenter or refer to by id.
+#[derive(Default)]
+pub struct ThreadReferences {
+    commenters: HashMap<String, i32>,
+    comments: HashSet<i32>,
+}
+
+impl ThreadReferences {
+    /// Creates the references from the ids and names of comments in the order
+    /// they were posted.
+    pub fn new(comments: Vec<(i32, String)>) -> ThreadReferences {
+        let mut references = ThreadReferences::default();
+        for (id, name) in comments {
+            let key = get_mention_key(&name);
+            if !key.is_empty() {
+                references.commenters.insert(key, id);
+            }
+            references.comments.insert(id);
+        }
+        references
+    }
+}
+
+pub struct RenderedComment {
+    pub html: String,
+    pub mentions: Vec<NewMention>,
+}
+
+/// Names are matched case-insensitively and without whitespace, so
+/// "Jane Doe" can be mentioned as `@janedoe`.
+fn get_mention_key(name: &str) -> String {
+    name.chars().filter(|c| !c.is_whitespace()).flat_map(char::to_lowercase).collect()
+}
+
+/// Splits a comma-separated setting into its non-empty items.
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(",").map(|item| item.trim()).filter(|item| !item.is_empty())
+}
+
+fn get_markdown_options(settings: &Settings) -> Options {
+    let mut options = Options::empty();
+    for extension in split_list(&settings.markdown_extensions) {
+        match extension {
+            "tables" => options.insert(Options::ENABLE_TABLES),
+            "strikethrough" => options.insert(Options::ENABLE_STRIKETHROUGH),
+            "footnotes" => options.insert(Options::ENABLE_FOOTNOTES),
+            "tasklists" => options.insert(Options::ENABLE_TASKLISTS),
+            _ => {},
+        }
+    }
+    options
+}
+
+fn get_sanitizer(settings: &Settings) -> ammonia::Builder<'_> {
+    let mut builder = ammonia::Builder::default();
+    let tags: HashSet<&str> = split_list(&settings.allowed_tags)
+        .filter(|tag| !CLEAN_CONTENT_TAGS.contains(tag))
+        .collect();
+    if !tags.is_empty() {
+        builder.tags(tags);
+    }
+    let link_rel = settings.link_rel.trim();
+    if !settings.allowed_attributes.trim().is_empty() {
+        let mut tag_attributes: HashMap<&str, HashSet<&str>> = HashMap::new();
+        let mut generic_attributes = HashSet::new();
+        for attribute in split_list(&settings.allowed_attributes) {
+            match attribute.split_once(".") {
+                // The rel attribute of links is set by ammonia when link_rel is
+                // used
+                Some(("*", "rel")) | Some(("a", "rel")) if !link_rel.is_empty() => {},
+                Some(("*", attribute)) => {
+                    generic_attributes.insert(attribute);
+                },
+                Some((tag, _)) if CLEAN_CONTENT_TAGS.contains(&tag) => {},
+                Some((tag, attribute)) => {
+                    tag_attributes.entry(tag).or_insert_with(HashSet::new).insert(attribute);
+                },
+                None => {},
+            }
+        }
+        builder.tag_attributes(tag_attributes).generic_attributes(generic_attributes);
+    }
+    if get_markdown_options(settings).contains(Options::ENABLE_TASKLISTS) {
+        builder.add_tags(&["input"]).add_tag_attributes("input", &["type", "checked", "disabled"]);
+    }
+    if settings.syntax_highlighting {
+        builder.add_tag_attributes("pre", &["class"])
+            .add_tag_attributes("span", &["class"])
+            .attribute_filter(|element, attribute, value| match (element, attribute) {
+                ("pre", "class") | ("span", "class") => {
+                    let classes: Vec<&str> = value.split_whitespace()
+                        .filter(|class| class.starts_with(CLASS_PREFIX))
+                        .collect();
+                    if classes.is_empty() { None } else { Some(classes.join(" ").into()) }
+                },
+                _ => Some(value.into()),
+            });
+    }
+    if !settings.allow_images {
+        builder.rm_tags(&["img"]);
+    }
+    builder.link_rel(if link_rel.is_empty() { None } else { Some(link_rel) });
+    let link_target = settings.link_target.trim();
+    if !link_target.is_empty() {
+        builder.rm_tag_attributes("a", &["target"])
+            .rm_generic_attributes(&["target"])
+            .set_tag_attribute_value("a", "target", link_target);
+    }
+    builder
+}
+
+/// Removes trailing punctuation that is more likely to be part of the
+/// surrounding sentence than of the URL.
+fn trim_url(url: &str) -> &str {
+    let punctuation = &['.', ',', ';', ':', '!', '?', '\'', '"'][..];
+    let mut url = url.trim_end_matches(punctuation);
+    while url.ends_with(")") && url.matches(")").count() > url.matches("(").count() {
+        url = url[..url.len() - 1].trim_end_matches(punctuation);
+    }
+    url
+}
+
+/// Converts plain URLs, mentions and references in a text into links.
+fn link_text(text: &str, references: &ThreadReferences, mentions: &mut Vec<NewMention>, events: &mut Vec<Event<'_>>) {
+    let mut start = 0;
+    for m in LINK_PATTERN.find_iter(text) {
+        let matched = m.as_str();
+        // Mentions and references can't be part of a word, e.g. an email address
+        let in_word = text[..m.start()].chars().next_back().map(|c| c.is_alphanumeric() || c == '_').unwrap_or(false);
+        let link = if matched.starts_with("http") {
+            let url = trim_url(matched);
+            Some((url.to_owned(), url))
+        } else if in_word {
+            None
+        } else {
+            let mention = match matched.strip_prefix("@") {
+                Some(name) => references.commenters.get(&get_mention_key(name))
+                    .map(|id| NewMention { target_id: *id, kind: MentionKind::Mention }),
+                None => matched[2..].parse::<i32>().ok()
+                    .filter(|id| references.comments.contains(id))
+                    .map(|id| NewMention { target_id: id, kind: MentionKind::Reference }),
+            };
+            mention.map(|mention| {
+                if !mentions.contains(&mention) {
+                    mentions.push(mention);
+                }
+                (format!("#comment-{}", mention.target_id), matched)
+            })
+        };
+        if let Some((url, label)) = link {
+            if m.start() > start {
+                events.push(Event::Text(text[start..m.start()].to_owned().into()));
+            }
+            events.push(Event::Start(Tag::Link(LinkType::Inline, url.clone().into(), "".into())));
+            events.push(Event::Text(label.to_owned().into()));
+            events.push(Event::End(Tag::Link(LinkType::Inline, url.into(), "".into())));
+            start = m.start() + label.len();
+        }
+    }
+    if start < text.len() {
+        events.push(Event::Text(text[start..].to_owned().into()));
+    }
+}
+
+/// Adds links to all text that isn't already part of a link or a code block.
+fn link_references<'a>(
+    parser: Parser<'a>,
+    references: &ThreadReferences,
+    mentions: &mut Vec<NewMention>,
+) -> Vec<Event<'a>> {
+    let mut events = Vec::new();
+    let mut text = String::new();
+    let mut link_depth = 0;
+    let mut in_code_block = false;
+    for event in parser {
+        if let Event::Text(t) = &event {
+            if link_depth == 0 && !in_code_block {
+                // Adjacent text events are joined so that patterns aren't split
+                text.push_str(t);
+                continue;
+            }
+        }
+        if !text.is_empty() {
+            link_text(&text, references, mentions, &mut events);
+            text.clear();
+        }
+        match &event {
+            Event::Start(Tag::Link(..)) | Event::Start(Tag::Image(..)) => link_depth += 1,
+            Event::End(Tag::Link(..)) | Event::End(Tag::Image(..)) => link_depth -= 1,
+            Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+            Event::End(Tag::CodeBlock(_)) => in_code_block = false,
+            _ => {},
+        }
+        events.push(event);
+    }
+    if !text.is_empty() {
+        link_text(&text, references, mentions, &mut events);
+    }
+    events
+}
+
+/// Highlights a code block using the first word of the info string of the
+/// fence as the language. Returns `None` if the language is unknown.
+fn highlight_code(info: &str, code: &str) -> Option<String> {
+    let language = info.split(|c: char| c.is_whitespace() || c == ',').next()?;
+    let syntax = SYNTAX_SET.find_syntax_by_token(language)?;
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAX_SET, CLASS_STYLE);
+    for line in LinesWithEndings::from(code) {
+        generator.parse_html_for_line_which_includes_newline(line).ok()?;
+    }
+    Some(format!("<pre class=\"{}code\"><code>{}</code></pre>\n", CLASS_PREFIX, generator.finalize()))
+}
+
+/// Replaces fenced code blocks with highlighted HTML.
+fn highlight_code_blocks(input: Vec<Event<'_>>) -> Vec<Event<'_>> {
+    let mut events = Vec::new();
+    let mut code_block = None;
+    for event in input {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                code_block = Some((info, String::new()));
+            },
+            Event::Text(text) if code_block.is_some() => {
+                if let Some((_, code)) = code_block.as_mut() {
+                    code.push_str(&text);
+                }
+            },
+            Event::End(Tag::CodeBlock(_)) if code_block.is_some() => {
+                if let Some((info, code)) = code_block.take() {
+                    match highlight_code(&info, &code) {
+                        Some(html) => events.push(Event::Html(html.into())),
+                        None => {
+                            events.push(Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info.clone()))));
+                            events.push(Event::Text(code.into()));
+                            events.push(Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(info))));
+                        },
+                    }
+                }
+            },
+            event => events.push(event),
+        }
+    }
+    events
+}
+
+/// Converts the markdown of a comment into the sanitized HTML that is stored
+/// and shown to readers. Mentions and references are resolved using the
+/// earlier comments in the thread.
+pub fn render_comment(markdown: &str, settings: &Settings, references: &ThreadReferences) -> RenderedComment {
+    let parser = Parser::new_ext(markdown, get_markdown_options(settings));
+    let mut mentions = Vec::new();
+    let mut events = link_references(parser, references, &mut mentions);
+    if settings.syntax_highlighting {
+        events = highlight_code_blocks(events);
+    }
+    let mut unsafe_html = String::new();
+    pulldown_cmark::html::push_html(&mut unsafe_html, events.into_iter());
+    RenderedComment { html: sanitize_html(&unsafe_html, settings), mentions }
+}
+
+/// Generates the stylesheet for highlighted code blocks using one of the
+/// built-in themes. The stylesheet is only generated once for each theme.
+pub fn get_highlight_css(theme: &str) -> Option<String> {
+    let mut cache = HIGHLIGHT_CSS.lock().unwrap();
+    cache.entry(theme.to_owned()).or_insert_with(|| {
+        let themes = ThemeSet::load_defaults();
+        css_for_theme_with_class_style(themes.themes.get(theme)?, CLASS_STYLE).ok()
+    }).clone()
+}
+
+/// Removes unsafe elements and attributes from HTML, used for imported
+/// comments which are already HTML.
+pub fn sanitize_html(html: &str, settings: &Settings) -> String {
+    get_sanitizer(settings).clean(html).to_string()
+}
+
+/// Checks that comments can be sanitized using the configured tags and
+/// attributes, so that invalid combinations are reported at startup instead of
+/// causing every render to fail.
+pub fn check_sanitizer(settings: &Settings) -> bool {
+    std::panic::catch_unwind(|| {
+        sanitize_html(r#"<p><a href="https://example.com" rel="nofollow">link</a><img src="a.png"></p>"#, settings);
+    }).is_ok()
+}
+
+/// Counts the links in sanitized HTML, not including links to other comments.
+pub fn count_links(html: &str) -> usize {
+    html.matches("<a ").count() - html.matches("<a href=\"#comment-").count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitizer_ignores_rel_when_link_rel_is_set() {
+        let mut settings = Settings::for_tests();
+        settings.allowed_attributes = "a.href,a.rel,*.rel".to_owned();
+        assert!(check_sanitizer(&settings));
+        assert_eq!(sanitize_html(r#"<a href="https://example.com" rel="me">x</a>"#, &settings),
+            r#"<a href="https://example.com" rel="nofollow ugc noopener">x</a>"#);
+    }
+
+    #[test]
+    fn sanitizer_allows_rel_without_link_rel() {
+        let mut settings = Settings::for_tests();
+        settings.allowed_attributes = "a.href,a.rel".to_owned();
+        settings.link_rel = "".to_owned();
+        assert_eq!(sanitize_html(r#"<a href="https://example.com" rel="me">x</a>"#, &settings),
+            r#"<a href="https://example.com" rel="me">x</a>"#);
+    }
+
+    #[test]
+    fn sanitizer_ignores_clean_content_tags() {
+        let mut settings = Settings::for_tests();
+        settings.allowed_tags = "p,script,style".to_owned();
+        settings.allowed_attributes = "script.src,style.media,p.title".to_owned();
+        assert!(check_sanitizer(&settings));
+        assert_eq!(sanitize_html(r#"<p title="a">b</p><script src="x.js">alert(1)</script>"#, &settings),
+            r#"<p title="a">b</p>"#);
+    }
+
+    #[test]
+    fn sanitizer_sets_link_target() {
+        let mut settings = Settings::for_tests();
+        settings.link_target = "_blank".to_owned();
+        settings.allowed_attributes = "a.href,a.target,*.target".to_owned();
+        assert!(check_sanitizer(&settings));
+        assert!(sanitize_html(r#"<a href="https://example.com" target="_self">x</a>"#, &settings)
+            .contains(r#"target="_blank""#));
+    }
+
+    #[test]
+    fn trims_trailing_punctuation_from_urls() {
+        assert_eq!(trim_url("https://example.com/."), "https://example.com/");
+        assert_eq!(trim_url("https://example.com/?a=b\","), "https://example.com/?a=b");
+        assert_eq!(trim_url("https://example.com/a)."), "https://example.com/a");
+        assert_eq!(trim_url("https://en.wikipedia.org/wiki/Rust_(language))"),
+            "https://en.wikipedia.org/wiki/Rust_(language)");
+    }
+
+    fn link<'a>(text: &'a str, references: &ThreadReferences) -> (Vec<Event<'a>>, Vec<NewMention>) {
+        let mut mentions = Vec::new();
+        let mut events = Vec::new();
+        link_text(text, references, &mut mentions, &mut events);
+        (events, mentions)
+    }
+
+    fn link_events(url: &str, label: &str) -> Vec<Event<'static>> {
+        vec![
+            Event::Start(Tag::Link(LinkType::Inline, url.to_owned().into(), "".into())),
+            Event::Text(label.to_owned().into()),
+            Event::End(Tag::Link(LinkType::Inline, url.to_owned().into(), "".into())),
+        ]
+    }
+
+    #[test]
+    fn links_urls_in_text() {
+        let (events, mentions) = link("See https://example.com/a.", &ThreadReferences::default());
+        let mut expected = vec![Event::Text("See ".to_owned().into())];
+        expected.extend(link_events("https://example.com/a", "https://example.com/a"));
+        expected.push(Event::Text(".".to_owned().into()));
+        assert_eq!(events, expected);
+        assert!(mentions.is_empty());
+    }
+
+    #[test]
+    fn links_mentions_and_references() {
+        let references = ThreadReferences::new(vec![(1, "Jane Doe".to_owned()), (2, "John".to_owned())]);
+        let (events, mentions) = link("@janedoe see #c2 and #c3, mail john@example.com", &references);
+        let mut expected = link_events("#comment-1", "@janedoe");
+        expected.push(Event::Text(" see ".to_owned().into()));
+        expected.extend(link_events("#comment-2", "#c2"));
+        expected.push(Event::Text(" and #c3, mail john@example.com".to_owned().into()));
+        assert_eq!(events, expected);
+        assert_eq!(mentions, vec![
+            NewMention { target_id: 1, kind: MentionKind::Mention },
+            NewMention { target_id: 2, kind: MentionKind::Reference },
+        ]);
+    }
+
+    #[test]
+    fn counts_external_links() {
+        assert_eq!(count_links("<p>no links</p>"), 0);
+        assert_eq!(count_links(r##"<a href="#comment-1">@a</a> <a href="https://example.com" rel="nofollow">x</a> <a href="https://example.org">y</a>"##), 2);
+    }
+}
@@ -0,0 +1,87 @@
+/* Copyright (c) 2021 Niels Sonnich Poulsen (http://nielssp.dk)
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Regeneration of the stored HTML of comments after the markdown or
+//! sanitization settings have changed
+
+use std::collections::HashMap;
+
+use similar::TextDiff;
+
+use crate::{db::{DbError, Pool, comments::{self, BulkFilter}, mentions}, render::{self, ThreadReferences}, settings::Settings};
+
+const BATCH_SIZE: usize = 500;
+
+#[derive(serde::Serialize)]
+pub struct ChangedComment {
+    pub id: i32,
+    pub thread_id: i32,
+    /// Unified diff between the stored and the new HTML
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct RerenderResult {
+    pub dry_run: bool,
+    pub checked: usize,
+    pub changed: Vec<ChangedComment>,
+}
+
+/// Renders the markdown of all comments matching the filter again and stores
+/// the HTML of the comments that changed, unless `dry_run` is set. Diffs are
+/// only generated for the first `max_diffs` changed comments.
+pub async fn rerender_comments(
+    pool: &Pool,
+    settings: &Settings,
+    filter: &BulkFilter,
+    dry_run: bool,
+    max_diffs: usize,
+) -> Result<RerenderResult, DbError> {
+    let mut commenters: HashMap<i32, Vec<(i32, String)>> = HashMap::new();
+    let mut result = RerenderResult { dry_run, checked: 0, changed: Vec::new() };
+    let mut after_id = 0;
+    loop {
+        let sources = comments::get_comment_sources(pool, filter, after_id, BATCH_SIZE).await?;
+        if sources.is_empty() {
+            break;
+        }
+        for source in sources {
+            after_id = source.id;
+            result.checked += 1;
+            if !commenters.contains_key(&source.thread_id) {
+                commenters.insert(source.thread_id,
+                    mentions::get_thread_commenters(pool, source.thread_id, None).await?);
+            }
+            // Mentions are resolved using the comments posted before this one
+            let earlier = commenters[&source.thread_id].iter()
+                .filter(|(id, _)| *id < source.id)
+                .cloned()
+                .collect();
+            let rendered = render::render_comment(&source.markdown, settings, &ThreadReferences::new(earlier));
+            if rendered.html == source.html {
+                continue;
+            }
+            if !dry_run {
+                comments::update_comment_html(pool, source.id, &rendered.html).await?;
+                mentions::set_mentions(pool, source.id, &rendered.mentions).await?;
+            }
+            let diff = if result.changed.len() < max_diffs {
+                Some(TextDiff::from_lines(&source.html, &rendered.html)
+                    .unified_diff()
+                    .header("before", "after")
+                    .to_string())
+            } else {
+                None
+            };
+            result.changed.push(ChangedComment {
+                id: source.id,
+                thread_id: source.thread_id,
+                diff,
+            });
+        }
+    }
+    Ok(result)
+}
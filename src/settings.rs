@@ -11,6 +11,7 @@ use config::{Config, ConfigError, Environment};
 pub struct Settings {
     pub listen: String,
     pub host: String,
+    pub base_url: Option<String>,
     pub forwarded: bool,
     pub database: String,
     pub secret_key: String,
@@ -18,18 +19,60 @@ pub struct Settings {
     pub argon2_memory_size: u32,
     pub rate_limit: i64,
     pub rate_limit_interval: i64,
+    pub preview_rate_limit: usize,
     pub auto_threads: bool,
     pub thread_url: Option<String>,
+    pub thread_normalization: String,
     pub require_name: bool,
     pub require_email: bool,
     pub moderate_all: bool,
     pub max_depth: u8,
+    pub close_after: i64,
+    pub close_after_first_comment: bool,
+    pub trash_retention: i64,
+    pub tombstones: String,
+    pub markdown_extensions: String,
+    pub allowed_tags: String,
+    pub allowed_attributes: String,
+    pub allow_images: bool,
+    pub link_rel: String,
+    pub link_target: String,
+    pub max_links: usize,
+    pub syntax_highlighting: bool,
+    pub highlight_theme: String,
+    pub attachments: bool,
+    pub attachment_storage: String,
+    pub attachment_dir: String,
+    pub attachment_url: String,
+    pub attachment_max_size: usize,
+    pub attachment_thumbnail_size: u32,
+    pub attachment_rate_limit: usize,
+    pub s3_endpoint: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_region: String,
+    pub s3_access_key: Option<String>,
+    pub s3_secret_key: Option<String>,
     pub default_admin_username: Option<String>,
     pub default_admin_password: Option<String>,
 }
 
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
+        let mut s = Settings::get_defaults()?;
+        s.merge(Environment::with_prefix("UNCOMMENT"))?;
+        s.try_into()
+    }
+
+    /// Default settings with a fixed host and secret key for unit tests.
+    #[cfg(test)]
+    pub fn for_tests() -> Settings {
+        let mut s = Settings::get_defaults().unwrap();
+        s.set("host", "https://example.com").unwrap();
+        s.set("secret_key", "secret").unwrap();
+        s.try_into().unwrap()
+    }
+
+    fn get_defaults() -> Result<Config, ConfigError> {
         let mut s = Config::default();
         s.set_default("listen", "127.0.0.1:5000")?;
         s.set_default("forwarded", false)?;
@@ -38,12 +81,34 @@ impl Settings {
         s.set_default("argon2_memory_size", 4096)?;
         s.set_default("rate_limit", 10)?;
         s.set_default("rate_limit_interval", 10)?;
+        s.set_default("preview_rate_limit", 60)?;
         s.set_default("auto_threads", true)?;
+        s.set_default("thread_normalization", "")?;
         s.set_default("require_name", false)?;
         s.set_default("require_email", false)?;
         s.set_default("moderate_all", false)?;
         s.set_default("max_depth", 6)?;
-        s.merge(Environment::with_prefix("UNCOMMENT"))?;
-        s.try_into()
+        s.set_default("close_after", 0)?;
+        s.set_default("close_after_first_comment", false)?;
+        s.set_default("trash_retention", 30)?;
+        s.set_default("tombstones", "Pending,Rejected")?;
+        s.set_default("markdown_extensions", "")?;
+        s.set_default("allowed_tags", "")?;
+        s.set_default("allowed_attributes", "")?;
+        s.set_default("allow_images", true)?;
+        s.set_default("link_rel", "nofollow ugc noopener")?;
+        s.set_default("link_target", "")?;
+        s.set_default("max_links", 0)?;
+        s.set_default("syntax_highlighting", false)?;
+        s.set_default("highlight_theme", "InspiredGitHub")?;
+        s.set_default("attachments", false)?;
+        s.set_default("attachment_storage", "local")?;
+        s.set_default("attachment_dir", "attachments")?;
+        s.set_default("attachment_url", "")?;
+        s.set_default("attachment_max_size", 5242880)?;
+        s.set_default("attachment_thumbnail_size", 400)?;
+        s.set_default("attachment_rate_limit", 10)?;
+        s.set_default("s3_region", "us-east-1")?;
+        Ok(s)
     }
 }